@@ -0,0 +1,23 @@
+//! Lists the versions of the migrations embedded by `embed_migrations!`, which keeps its
+//! own list private, so that databases migrated by newer builds can be told apart.
+
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    for backend in ["sqlite", "postgres"] {
+        let dir = Path::new("migrations").join(backend);
+        println!("cargo:rerun-if-changed={}", dir.display());
+        // Named like diesel_migrations does, the date and time without dashes.
+        let mut versions = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.'))
+            .map(|name| name.split('_').next().unwrap().replace('-', ""))
+            .collect::<Vec<_>>();
+        versions.sort();
+        fs::write(Path::new(&out_dir).join(format!("{}_versions.rs", backend)), format!("&{:?}", versions)).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+CREATE TABLE paths (
+    id                text primary key not null,
+    path              text not null,
+    registration_time bigint,
+    last_accessed     bigint,
+    nar_size          integer not null,
+    nar_hash          text not null,
+    file_size         integer,
+    file_hash         text,
+    url               text,
+    compression       text,
+    deriver           text,
+    ca                text,
+    sigs              text not null,
+    refs              text not null
+)
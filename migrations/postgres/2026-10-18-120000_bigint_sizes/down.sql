@@ -0,0 +1,3 @@
+ALTER TABLE paths
+    ALTER COLUMN nar_size TYPE integer,
+    ALTER COLUMN file_size TYPE integer;
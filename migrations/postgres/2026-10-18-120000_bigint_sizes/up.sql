@@ -0,0 +1,3 @@
+ALTER TABLE paths
+    ALTER COLUMN nar_size TYPE bigint,
+    ALTER COLUMN file_size TYPE bigint;
@@ -0,0 +1,5 @@
+ALTER TABLE paths ADD COLUMN refs text not null default '';
+UPDATE paths SET refs = coalesce((SELECT string_agg(reference, ' ') FROM refs WHERE referrer = paths.id), '');
+ALTER TABLE paths ALTER COLUMN refs DROP DEFAULT;
+DROP INDEX paths_path;
+DROP TABLE refs;
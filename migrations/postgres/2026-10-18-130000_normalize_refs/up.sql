@@ -0,0 +1,14 @@
+CREATE TABLE refs (
+    referrer  text not null,
+    reference text not null,
+    primary key (referrer, reference)
+);
+CREATE INDEX refs_reference ON refs (reference);
+CREATE INDEX paths_path ON paths (path);
+
+INSERT INTO refs
+SELECT DISTINCT id, reference
+FROM paths, unnest(string_to_array(refs, ' ')) AS reference
+WHERE reference <> '';
+
+ALTER TABLE paths DROP COLUMN refs;
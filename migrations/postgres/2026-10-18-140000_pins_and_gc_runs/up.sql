@@ -0,0 +1,16 @@
+CREATE TABLE pins (
+    id      text primary key not null,
+    created bigint not null,
+    expires bigint,
+    note    text
+);
+
+CREATE TABLE gc_runs (
+    id             bigserial primary key,
+    started        bigint not null,
+    finished       bigint not null,
+    total_bytes    bigint not null,
+    pinned_bytes   bigint not null,
+    evicted_paths  bigint not null,
+    evicted_bytes  bigint not null
+);
@@ -0,0 +1,12 @@
+-- Backend objects with the number of paths that use them, so that paths with the same
+-- compressed NAR can share one object.
+CREATE TABLE objects (
+    url       text primary key not null,
+    file_hash text,
+    refcount  bigint not null
+);
+
+CREATE INDEX objects_file_hash ON objects (file_hash);
+
+INSERT INTO objects (url, file_hash, refcount)
+    SELECT url, MIN(file_hash), COUNT(*) FROM paths WHERE url IS NOT NULL GROUP BY url;
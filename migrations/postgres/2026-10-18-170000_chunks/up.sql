@@ -0,0 +1,14 @@
+-- Chunks of decompressed NARs, stored once in the backend however many objects use them.
+CREATE TABLE chunks (
+    hash     text primary key not null,
+    size     bigint not null,
+    refcount bigint not null
+);
+
+-- The chunks an object is reassembled from, in order.
+CREATE TABLE object_chunks (
+    url  text not null,
+    seq  bigint not null,
+    hash text not null,
+    primary key (url, seq)
+);
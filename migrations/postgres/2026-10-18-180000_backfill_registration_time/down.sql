@@ -0,0 +1,2 @@
+-- Which paths had no registration time is not recorded.
+SELECT 1;
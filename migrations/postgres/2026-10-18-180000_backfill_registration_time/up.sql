@@ -0,0 +1,5 @@
+-- Paths cached before registration times were recorded would never expire by age, so
+-- they count as registered when they were last used, or else now.
+UPDATE paths
+    SET registration_time = COALESCE(last_accessed, CAST(EXTRACT(EPOCH FROM now()) AS bigint))
+    WHERE registration_time IS NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE gc_runs DROP COLUMN inflation;
+ALTER TABLE paths DROP COLUMN inflation;
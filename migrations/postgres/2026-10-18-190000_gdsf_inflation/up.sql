@@ -0,0 +1,2 @@
+ALTER TABLE paths ADD COLUMN inflation double precision not null default 0;
+ALTER TABLE gc_runs ADD COLUMN inflation double precision not null default 0;
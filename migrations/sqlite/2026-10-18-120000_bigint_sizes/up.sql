@@ -0,0 +1,40 @@
+-- Sizes used to be stored after a lossy cast to a signed 32 bit integer. NARs between
+-- 2 GiB and 4 GiB wrapped around to negative values and can be recovered, larger ones
+-- lost their upper bits for good and need to be re-uploaded.
+CREATE TABLE paths_new (
+    id                text primary key not null,
+    path              text not null,
+    registration_time unsigned bigint,
+    last_accessed     unsigned bigint,
+    nar_size          unsigned bigint not null,
+    nar_hash          text not null,
+    file_size         unsigned bigint,
+    file_hash         text,
+    url               text,
+    compression       text,
+    deriver           text,
+    ca                text,
+    sigs              text not null,
+    refs              text not null
+);
+
+INSERT INTO paths_new
+SELECT
+    id,
+    path,
+    registration_time,
+    last_accessed,
+    CASE WHEN nar_size < 0 THEN nar_size + 4294967296 ELSE nar_size END,
+    nar_hash,
+    CASE WHEN file_size < 0 THEN file_size + 4294967296 ELSE file_size END,
+    file_hash,
+    url,
+    compression,
+    deriver,
+    ca,
+    sigs,
+    refs
+FROM paths;
+
+DROP TABLE paths;
+ALTER TABLE paths_new RENAME TO paths;
@@ -0,0 +1,26 @@
+CREATE TABLE paths_old (
+    id                text primary key not null,
+    path              text not null,
+    registration_time unsigned bigint,
+    last_accessed     unsigned bigint,
+    nar_size          unsigned bigint not null,
+    nar_hash          text not null,
+    file_size         unsigned bigint,
+    file_hash         text,
+    url               text,
+    compression       text,
+    deriver           text,
+    ca                text,
+    sigs              text not null,
+    refs              text not null
+);
+
+INSERT INTO paths_old
+SELECT id, path, registration_time, last_accessed, nar_size, nar_hash, file_size, file_hash,
+       url, compression, deriver, ca, sigs,
+       coalesce((SELECT group_concat(reference, ' ') FROM refs WHERE referrer = paths.id), '')
+FROM paths;
+
+DROP TABLE paths;
+ALTER TABLE paths_old RENAME TO paths;
+DROP TABLE refs;
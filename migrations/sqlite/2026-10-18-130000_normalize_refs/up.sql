@@ -0,0 +1,39 @@
+CREATE TABLE refs (
+    referrer  text not null,
+    reference text not null,
+    primary key (referrer, reference)
+);
+CREATE INDEX refs_reference ON refs (reference);
+
+WITH RECURSIVE split(referrer, reference, rest) AS (
+    SELECT id, '', refs || ' ' FROM paths
+    UNION ALL
+    SELECT referrer, substr(rest, 1, instr(rest, ' ') - 1), substr(rest, instr(rest, ' ') + 1)
+    FROM split WHERE rest <> ''
+)
+INSERT INTO refs SELECT DISTINCT referrer, reference FROM split WHERE reference <> '';
+
+CREATE TABLE paths_new (
+    id                text primary key not null,
+    path              text not null,
+    registration_time unsigned bigint,
+    last_accessed     unsigned bigint,
+    nar_size          unsigned bigint not null,
+    nar_hash          text not null,
+    file_size         unsigned bigint,
+    file_hash         text,
+    url               text,
+    compression       text,
+    deriver           text,
+    ca                text,
+    sigs              text not null
+);
+
+INSERT INTO paths_new
+SELECT id, path, registration_time, last_accessed, nar_size, nar_hash, file_size, file_hash,
+       url, compression, deriver, ca, sigs
+FROM paths;
+
+DROP TABLE paths;
+ALTER TABLE paths_new RENAME TO paths;
+CREATE INDEX paths_path ON paths (path);
@@ -0,0 +1,2 @@
+DROP TABLE gc_runs;
+DROP TABLE pins;
@@ -0,0 +1 @@
+ALTER TABLE paths ADD COLUMN hit_count bigint not null default 0;
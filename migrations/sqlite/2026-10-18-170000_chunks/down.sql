@@ -0,0 +1,2 @@
+DROP TABLE object_chunks;
+DROP TABLE chunks;
@@ -5,6 +5,7 @@ mkShell {
   buildInputs = [
     cargo rustc
     sqlite
+    postgresql
     diesel-cli
   ];
 }
@@ -0,0 +1,46 @@
+//! Access times and download counts of paths, which are collected in memory and written
+//! in batches instead of costing a write on every request.
+
+use crate::db::{db_run, DbConn};
+use crate::error::Result;
+use crate::models::{now, Access, PathQueries};
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// How often recorded accesses are written to the database. Accesses since the last flush
+/// are lost when the server stops.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct Accesses(Mutex<HashMap<String, Access>>);
+
+impl Accesses {
+    /// Notes that a path was requested just now, and with `download` that its NAR was.
+    pub async fn record(&self, id: &str, download: bool) {
+        let mut pending = self.0.lock().await;
+        let access = pending.entry(id.to_string()).or_default();
+        access.last_accessed = now();
+        access.hits += download as i64;
+    }
+
+    /// Writes the accesses recorded so far, or keeps them for the next flush if that fails.
+    pub async fn flush(&self, conn: &DbConn) -> Result<()> {
+        let accesses = std::mem::take(&mut *self.0.lock().await);
+        if accesses.is_empty() {
+            return Ok(());
+        }
+        let written = accesses.clone();
+        if let Err(e) = db_run!(conn, |c| c.record_accesses(&written)) {
+            let mut pending = self.0.lock().await;
+            for (id, access) in accesses {
+                let merged = pending.entry(id).or_default();
+                merged.last_accessed = merged.last_accessed.max(access.last_accessed);
+                merged.hits += access.hits;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+// Rocket's route attribute re-exports a generated `uri!` macro for every handler, which
+// nothing outside of this module uses.
+#![allow(unused_imports)]
+
+use crate::auth::Admin;
+use crate::backend::NarResponder;
+use crate::chunks::{ChunkQueries, ChunkStats};
+use crate::db::{db_run, DbConn};
+use crate::error::{Error, Result};
+use crate::gc::{path_id, GcQueries};
+use crate::models::{now, DbGcRun, DbPath, DbPin, PathQueries};
+
+use rocket::data::{ByteUnit, Data, ToByteUnit};
+use rocket::http::ContentType;
+use rocket::serde::json::Json;
+use std::collections::HashSet;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![get_closure, get_referrers, get_pins, put_pin, delete_pin, get_gc_runs, get_chunk_stats, post_missing, post_import, get_export]
+}
+
+#[derive(Debug, Serialize)]
+pub struct Closure {
+    pub paths: Vec<DbPath>,
+    /// Store paths in the closure that are not in the cache.
+    pub missing: Vec<String>,
+    pub nar_size: i64,
+    pub file_size: i64,
+}
+
+#[rocket::get("/closure/<id>")]
+async fn get_closure(conn: DbConn, id: &str) -> Result<Json<Closure>> {
+    let id = path_id(id).to_string();
+    let (paths, missing) = db_run!(conn, |c| {
+        c.closure(&id).and_then(|paths| Ok((paths, c.missing_references(&id)?)))
+    })?;
+    if paths.is_empty() {
+        return Err(Error::NotFound);
+    }
+    Ok(Json(Closure {
+        nar_size: paths.iter().map(|path| path.nar_size).sum(),
+        file_size: paths.iter().filter_map(|path| path.file_size).sum(),
+        paths,
+        missing,
+    }))
+}
+
+#[rocket::get("/referrers/<id>")]
+async fn get_referrers(conn: DbConn, id: &str) -> Result<Json<Vec<DbPath>>> {
+    let id = path_id(id).to_string();
+    let (exists, referrers) = db_run!(conn, |c| {
+        c.load_path(&id).and_then(|path| Ok((path.is_some(), c.referrers(&id)?)))
+    })?;
+    if !exists {
+        return Err(Error::NotFound);
+    }
+    Ok(Json(referrers))
+}
+
+/// Most hashes a single `/missing` request may ask about, which keeps the query below
+/// the bind parameter limit of SQLite.
+const MAX_MISSING_QUERY: usize = 30_000;
+
+/// Bytes a full store path takes up in the JSON body at most, with some room to spare.
+const MAX_STORE_PATH_JSON: usize = 300;
+
+/// Takes store path hashes or full store paths and returns those not in the cache, as
+/// they were given, so that clients can push a closure without asking for every narinfo.
+///
+/// The body is read with a limit of its own, since `MAX_MISSING_QUERY` store paths take
+/// up more than Rocket's default `limits.json`.
+#[rocket::post("/missing", data = "<data>")]
+async fn post_missing(conn: DbConn, data: Data<'_>) -> Result<Json<Vec<String>>> {
+    let body = data.open((MAX_MISSING_QUERY * MAX_STORE_PATH_JSON).bytes()).into_bytes().await?;
+    if !body.is_complete() {
+        return Err(Error::TooManyPaths(MAX_MISSING_QUERY));
+    }
+    let paths = serde_json::from_slice::<Vec<String>>(&body).map_err(Error::BadJson)?;
+    if paths.len() > MAX_MISSING_QUERY {
+        return Err(Error::TooManyPaths(MAX_MISSING_QUERY));
+    }
+    let ids = paths.iter().map(|path| path_id(path).to_string()).collect::<Vec<_>>();
+    let missing = db_run!(conn, |c| c.missing_paths(&ids))?.into_iter().collect::<HashSet<_>>();
+    Ok(Json(paths.into_iter().filter(|path| missing.contains(path_id(path))).collect()))
+}
+
+/// Imports the output of `nix-store --export` and returns the imported store paths.
+#[rocket::post("/import", data = "<data>")]
+async fn post_import(
+    _admin: Admin,
+    conn: DbConn,
+    data: Data<'_>,
+    state: &rocket::State<Arc<crate::State>>,
+) -> Result<Json<Vec<String>>> {
+    Ok(Json(crate::import::import(&conn, state, data.open(ByteUnit::max_value())).await?))
+}
+
+/// Streams the closure of a path for `nix-store --import`, dependencies first.
+#[rocket::get("/export/<id>")]
+async fn get_export(
+    conn: DbConn,
+    id: &str,
+    state: &rocket::State<Arc<crate::State>>,
+) -> Result<(ContentType, NarResponder)> {
+    let export = crate::export::export_closure(&conn, state.backend.clone(), path_id(id)).await?;
+    Ok((ContentType::Binary, NarResponder::Reader(export)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PinRequest {
+    /// Seconds since the epoch after which the pin no longer applies.
+    pub expires: Option<i64>,
+    pub note: Option<String>,
+}
+
+#[rocket::get("/pins")]
+async fn get_pins(conn: DbConn) -> Result<Json<Vec<DbPin>>> {
+    Ok(Json(db_run!(conn, |c| c.pins())?))
+}
+
+#[rocket::put("/pins/<id>", data = "<request>")]
+async fn put_pin(_admin: Admin, conn: DbConn, id: &str, request: Option<Json<PinRequest>>) -> Result<Json<DbPin>> {
+    let request = request.map(|request| request.into_inner()).unwrap_or_default();
+    let pin = DbPin {
+        id: path_id(id).to_string(),
+        created: now(),
+        expires: request.expires,
+        note: request.note,
+    };
+    let stored = pin.clone();
+    let exists = db_run!(conn, |c| {
+        c.load_path(&stored.id).and_then(|path| match path {
+            Some(_) => c.pin(&stored).map(|_| true),
+            None => Ok(false),
+        })
+    })?;
+    if !exists {
+        return Err(Error::NotFound);
+    }
+    Ok(Json(pin))
+}
+
+#[rocket::delete("/pins/<id>")]
+async fn delete_pin(_admin: Admin, conn: DbConn, id: &str) -> Result<()> {
+    let id = path_id(id).to_string();
+    match db_run!(conn, |c| c.unpin(&id))? {
+        0 => Err(Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+#[rocket::get("/gc/runs")]
+async fn get_gc_runs(conn: DbConn) -> Result<Json<Vec<DbGcRun>>> {
+    Ok(Json(db_run!(conn, |c| c.gc_runs(20))?))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DedupStats {
+    #[serde(flatten)]
+    pub chunks: ChunkStats,
+    pub dedup_ratio: f64,
+}
+
+#[rocket::get("/chunks/stats")]
+async fn get_chunk_stats(conn: DbConn) -> Result<Json<DedupStats>> {
+    let chunks = db_run!(conn, |c| c.chunk_stats())?;
+    Ok(Json(DedupStats { dedup_ratio: chunks.dedup_ratio(), chunks }))
+}
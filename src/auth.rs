@@ -0,0 +1,34 @@
+use crate::config::Config;
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+/// Request guard for administrative endpoints, which require
+/// `Authorization: Bearer <admin_token>`.
+///
+/// Without a configured `admin_token` every request is refused.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let expected = match req.rocket().state::<Config>().and_then(|config| config.admin_token.as_deref()) {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Forbidden, ())),
+        };
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match given {
+            Some(given) if ring::constant_time::verify_slices_are_equal(given.as_bytes(), expected.as_bytes()).is_ok() => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
@@ -1,26 +1,71 @@
-use super::{Backend, NarResponder};
-use tokio::io::BufWriter;
+use super::{Area, Backend, NarResponder, ObjectInfo};
+use tokio::io::{AsyncRead, BufWriter};
 use tokio::fs;
-use std::path::PathBuf;
-use rocket::data::DataStream;
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rocket::data::ByteUnit;
+use serde::Deserialize;
 use crate::error::{Error, Result};
 
+/// Free space thresholds on the filesystem of `data_dir`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Watermarks {
+    /// Start evicting paths when less than this is free.
+    #[serde(deserialize_with = "crate::config::byte_unit")]
+    pub low: ByteUnit,
+    /// Stop evicting once this much is free again.
+    #[serde(deserialize_with = "crate::config::byte_unit")]
+    pub high: ByteUnit,
+    /// Refuse uploads while less than this is free.
+    #[serde(deserialize_with = "crate::config::byte_unit")]
+    pub critical: ByteUnit,
+}
+
+/// Measures the free space on the filesystem containing a directory.
+pub type FreeSpace = Arc<dyn Fn(&Path) -> std::io::Result<u64> + Send + Sync>;
+
 pub struct LocalBackend {
     tmp_dir: PathBuf,
     data_dir: PathBuf,
+    quarantine_dir: PathBuf,
+    watermarks: Option<Watermarks>,
+    measure_free_space: FreeSpace,
 }
 
 impl LocalBackend {
-    pub fn new<T: Into<PathBuf>, U: Into<PathBuf>>(tmp_dir: T, data_dir: U) -> Self {
+    pub fn new<T: Into<PathBuf>, U: Into<PathBuf>, V: Into<PathBuf>>(tmp_dir: T, data_dir: U, quarantine_dir: V) -> Self {
         Self {
             tmp_dir: tmp_dir.into(),
             data_dir: data_dir.into(),
+            quarantine_dir: quarantine_dir.into(),
+            watermarks: None,
+            measure_free_space: Arc::new(free_space),
         }
     }
-    pub fn new_current_dir() -> Result<Self> {
-        let current_dir = std::env::current_dir()?;
-        let backend = Self::new(current_dir.join("tmp"), current_dir.join("data"));
-        Ok(backend)
+
+    pub fn with_watermarks(self, watermarks: Option<Watermarks>) -> Self {
+        Self { watermarks, ..self }
+    }
+
+    /// Replaces `statvfs` for measuring free space, so that tests can fill up the disk.
+    #[cfg(test)]
+    pub fn with_free_space(self, measure_free_space: FreeSpace) -> Self {
+        Self { measure_free_space, ..self }
+    }
+
+    async fn free_space(&self) -> Result<u64> {
+        fs::create_dir_all(&self.data_dir).await?;
+        Ok((self.measure_free_space)(&self.data_dir)?)
+    }
+
+    fn area_dir(&self, area: Area) -> &Path {
+        match area {
+            Area::Tmp => &self.tmp_dir,
+            Area::Data => &self.data_dir,
+        }
     }
 }
 
@@ -28,10 +73,18 @@ impl LocalBackend {
 impl Backend for LocalBackend {
     async fn read_nar(&self, url: &str) -> Result<NarResponder> {
         let path = self.data_dir.join(url);
-        let file = fs::File::open(&path).await?;
+        let file = match fs::File::open(&path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Error::NotFound),
+            file => file?,
+        };
         Ok(NarResponder::File(file))
     }
-    async fn write_nar(&self, url: &str, reader: &mut DataStream<'_>) -> Result<()> {
+    async fn write_nar(&self, url: &str, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<()> {
+        if let Some(watermarks) = &self.watermarks {
+            if self.free_space().await? < watermarks.critical.as_u64() {
+                return Err(Error::DiskFull);
+            }
+        }
         let path = self.tmp_dir.join(url);
         fs::create_dir_all(&path.parent().ok_or(Error::Upload)?).await?;
         let mut file = fs::File::create(&path).await?;
@@ -45,4 +98,115 @@ impl Backend for LocalBackend {
         fs::rename(&tmppath, newpath).await?;
         Ok(())
     }
+    async fn delete_nar(&self, url: &str) -> Result<()> {
+        remove_file_if_exists(&self.data_dir.join(url)).await
+    }
+    async fn abort_nar(&self, url: &str) -> Result<()> {
+        remove_file_if_exists(&self.tmp_dir.join(url)).await
+    }
+    async fn quarantine_nar(&self, url: &str) -> Result<()> {
+        let newpath = self.quarantine_dir.join(url);
+        fs::create_dir_all(&newpath.parent().ok_or(Error::Backend)?).await?;
+        fs::rename(self.data_dir.join(url), newpath).await?;
+        Ok(())
+    }
+    async fn list(&self, area: Area) -> Result<Vec<ObjectInfo>> {
+        let root = self.area_dir(area);
+        let mut objects = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                entries => entries?,
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let path = entry.path();
+                let url = path.strip_prefix(root).map_err(|_| Error::Backend)?;
+                objects.push(ObjectInfo {
+                    url: url.to_str().ok_or(Error::Backend)?.to_string(),
+                    size: metadata.len(),
+                    last_modified: metadata.modified()?,
+                });
+            }
+        }
+        Ok(objects)
+    }
+    async fn space_to_free(&self) -> Result<u64> {
+        let watermarks = match &self.watermarks {
+            Some(watermarks) => watermarks,
+            None => return Ok(0),
+        };
+        let free = self.free_space().await?;
+        if free >= watermarks.low.as_u64() {
+            return Ok(0);
+        }
+        Ok(watermarks.high.as_u64().saturating_sub(free))
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem containing `path`.
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read after statvfs filled it in.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+async fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use rocket::data::ToByteUnit;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A backend in `root` on a disk with as much free space as `free` says.
+    fn filling_backend(root: &Path, free: Arc<AtomicU64>) -> LocalBackend {
+        let watermarks = Watermarks { low: 100.bytes(), high: 300.bytes(), critical: 50.bytes() };
+        LocalBackend::new(root.join("tmp"), root.join("data"), root.join("quarantine"))
+            .with_watermarks(Some(watermarks))
+            .with_free_space(Arc::new(move |_| Ok(free.load(Ordering::SeqCst))))
+    }
+
+    #[rocket::async_test]
+    async fn watermarks() {
+        let root = crate::tests::test_root("watermarks");
+        let free = Arc::new(AtomicU64::new(1000));
+        let backend = filling_backend(&root, free.clone());
+        backend.write_nar("a.nar", &mut &b"a"[..]).await.unwrap();
+        assert_eq!(backend.space_to_free().await.unwrap(), 0);
+
+        // Nothing is evicted until the disk falls below the low watermark, then enough to
+        // get back to the high one.
+        free.store(100, Ordering::SeqCst);
+        assert_eq!(backend.space_to_free().await.unwrap(), 0);
+        free.store(80, Ordering::SeqCst);
+        assert_eq!(backend.space_to_free().await.unwrap(), 220);
+        backend.write_nar("b.nar", &mut &b"b"[..]).await.unwrap();
+
+        free.store(40, Ordering::SeqCst);
+        assert_eq!(backend.space_to_free().await.unwrap(), 260);
+        assert_matches!(backend.write_nar("c.nar", &mut &b"c"[..]).await, Err(Error::DiskFull));
+        // Finishing and deleting uploads still works, which is how space is freed.
+        backend.finish_nar("a.nar").await.unwrap();
+        backend.delete_nar("a.nar").await.unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -1,17 +1,58 @@
 pub mod local;
 pub mod s3;
+pub mod store;
 
+use std::time::SystemTime;
 use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
 use crate::error::Result;
+use crate::nixutils::NarInfo;
 use rocket::futures::StreamExt;
 use rocket::Request;
-use rocket::data::DataStream;
-use rocket::response::Responder;
+use rocket::response::{Responder, Response};
 use rocket::response::stream::ByteStream;
 
+/// The storage areas a backend keeps objects in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    Tmp,
+    Data,
+}
+
+impl Area {
+    pub fn dir(&self) -> &'static str {
+        match self {
+            Area::Tmp => "tmp",
+            Area::Data => "data",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub url: String,
+    pub size: u64,
+    pub last_modified: SystemTime,
+}
+
 pub enum NarResponder {
     File(File),
     Stream(hyper::Body),
+    /// Data produced on the fly, such as a NAR reassembled from chunks.
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+impl NarResponder {
+    pub fn into_reader(self) -> Box<dyn AsyncRead + Send + Unpin> {
+        match self {
+            NarResponder::File(file) => Box::new(file),
+            NarResponder::Stream(stream) => Box::new(StreamReader::new(stream.map(|x| {
+                x.map_err(std::io::Error::other)
+            }))),
+            NarResponder::Reader(reader) => reader,
+        }
+    }
 }
 
 impl<'r> Responder<'r, 'r> for NarResponder {
@@ -19,9 +60,10 @@ impl<'r> Responder<'r, 'r> for NarResponder {
         let response = match self {
             NarResponder::File(file) => file.respond_to(req)?,
             NarResponder::Stream(stream) => {
-                let foo = ByteStream::from(stream.map(|x| x.unwrap()));
-                foo.respond_to(req)?
+                let stream = ByteStream::from(stream.map(|x| x.unwrap()));
+                stream.respond_to(req)?
             },
+            NarResponder::Reader(reader) => Response::build().streamed_body(reader).finalize(),
         };
         Ok(response)
     }
@@ -30,6 +72,21 @@ impl<'r> Responder<'r, 'r> for NarResponder {
 #[async_trait::async_trait]
 pub trait Backend {
     async fn read_nar(&self, url: &str) -> Result<NarResponder>;
-    async fn write_nar(&self, url: &str, reader: &mut DataStream<'_>) -> Result<()>;
+    async fn write_nar(&self, url: &str, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<()>;
     async fn finish_nar(&self, url: &str) -> Result<()>;
+    async fn delete_nar(&self, url: &str) -> Result<()>;
+    /// Deletes an upload that was written but never finished.
+    async fn abort_nar(&self, url: &str) -> Result<()>;
+    /// Moves a stored NAR out of the served data into a quarantine area for later inspection.
+    async fn quarantine_nar(&self, url: &str) -> Result<()>;
+    async fn list(&self, area: Area) -> Result<Vec<ObjectInfo>>;
+    /// How many bytes have to be evicted to relieve pressure on the underlying storage.
+    async fn space_to_free(&self) -> Result<u64> {
+        Ok(0)
+    }
+    /// Describes a path that the backend can serve without it being uploaded, such as one
+    /// in a local store, by the hash part of its name.
+    async fn find_path(&self, _id: &str) -> Result<Option<NarInfo>> {
+        Ok(None)
+    }
 }
@@ -1,14 +1,16 @@
-use super::{Backend, NarResponder};
+use super::{Area, Backend, NarResponder, ObjectInfo};
 use s3::bucket::Bucket;
 use s3::command::{Command, HttpMethod};
 use s3::request::Reqwest;
 use s3::request_trait::Request;
-use rocket::data::DataStream;
+use tokio::io::AsyncRead;
 use crate::error::{Error, Result};
+use log::debug;
 use cached::proc_macro::cached;
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper::{Method, Client, Body, client::HttpConnector};
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 #[cached]
 pub fn https_client() -> Client<HttpsConnector<HttpConnector>, Body> {
@@ -58,15 +60,20 @@ impl Backend for Bucket {
         let request = request.hyper_request().map_err(|_| Error::Download)?;
         let client = https_client();
         let response = client.request(request).await.map_err(|_| Error::Download)?;
+        if response.status() == hyper::StatusCode::NOT_FOUND {
+            return Err(Error::NotFound);
+        } else if !response.status().is_success() {
+            return Err(Error::Download);
+        }
         let responder = NarResponder::Stream(response.into_body());
         Ok(responder)
     }
-    async fn write_nar(&self, url: &str, reader: &mut DataStream<'_>) -> Result<()> {
+    async fn write_nar(&self, url: &str, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<()> {
         let tmp_dir = PathBuf::from("tmp");
         let path = tmp_dir.join(url);
         let path = path.to_str().ok_or(Error::Upload)?;
-        println!("uploading {}", path);
-        self.put_object_stream(reader, path).await.map_err(|_| Error::Upload)?;
+        debug!("uploading {}", path);
+        self.put_object_stream(&mut &mut *reader, path).await.map_err(|_| Error::Upload)?;
         Ok(())
     }
     async fn finish_nar(&self, url: &str) -> Result<()> {
@@ -78,7 +85,47 @@ impl Backend for Bucket {
         let newpath = newpath.to_str().ok_or(Error::Upload)?;
         self.copy_object_internal(tmppath, newpath).await.map_err(|_| Error::Upload)?;
         self.delete_object(tmppath).await.map_err(|_| Error::Upload)?;
-        println!("finished {}", newpath);
+        debug!("finished {}", newpath);
         Ok(())
     }
+    async fn delete_nar(&self, url: &str) -> Result<()> {
+        let data_dir = PathBuf::from("data");
+        let path = data_dir.join(url);
+        let path = path.to_str().ok_or(Error::Backend)?;
+        self.delete_object(path).await.map_err(|_| Error::Backend)?;
+        Ok(())
+    }
+    async fn abort_nar(&self, url: &str) -> Result<()> {
+        let tmp_dir = PathBuf::from("tmp");
+        let path = tmp_dir.join(url);
+        let path = path.to_str().ok_or(Error::Backend)?;
+        self.delete_object(path).await.map_err(|_| Error::Backend)?;
+        Ok(())
+    }
+    async fn quarantine_nar(&self, url: &str) -> Result<()> {
+        let data_dir = PathBuf::from("data");
+        let quarantine_dir = PathBuf::from("quarantine");
+        let path = data_dir.join(url);
+        let newpath = quarantine_dir.join(url);
+        let path = path.to_str().ok_or(Error::Backend)?;
+        let newpath = newpath.to_str().ok_or(Error::Backend)?;
+        self.copy_object_internal(path, newpath).await.map_err(|_| Error::Backend)?;
+        self.delete_object(path).await.map_err(|_| Error::Backend)?;
+        Ok(())
+    }
+    async fn list(&self, area: Area) -> Result<Vec<ObjectInfo>> {
+        let prefix = format!("{}/", area.dir());
+        let results = self.list(prefix.clone(), None).await.map_err(|_| Error::Backend)?;
+        let mut objects = Vec::new();
+        for object in results.into_iter().flat_map(|result| result.contents) {
+            let last_modified = chrono::DateTime::parse_from_rfc3339(&object.last_modified)
+                .map_err(|_| Error::Backend)?;
+            objects.push(ObjectInfo {
+                url: object.key.strip_prefix(&prefix).ok_or(Error::Backend)?.to_string(),
+                size: object.size,
+                last_modified: SystemTime::from(last_modified),
+            });
+        }
+        Ok(objects)
+    }
 }
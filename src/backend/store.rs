@@ -0,0 +1,292 @@
+//! Serves the paths of a local Nix store, like nix-serve does.
+//!
+//! Nothing is uploaded to this backend. Paths are described by a metadata file or Nix's
+//! own database, and their NARs are generated from the store directory on every download.
+//! Listings are generated on their first request, and kept in memory.
+
+use super::{Area, Backend, NarResponder, ObjectInfo};
+use crate::error::{Error, Result};
+use crate::gc::path_id;
+use crate::listing;
+use crate::nixutils::nar;
+use crate::nixutils::store_db::StoreDb;
+use crate::nixutils::{Compression, NarInfo, NixHash, Signature};
+use log::warn;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
+
+/// How much of a generated NAR is buffered ahead of its compression.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Most listings kept in memory.
+const MAX_LISTINGS: usize = 10_000;
+
+/// A store path as described by `nix path-info --json`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfo {
+    /// Missing when the paths are the keys of an object, as printed by newer versions of Nix.
+    #[serde(default)]
+    path: String,
+    nar_hash: String,
+    nar_size: u64,
+    #[serde(default)]
+    references: Vec<String>,
+    deriver: Option<String>,
+    #[serde(default)]
+    signatures: Vec<String>,
+    ca: Option<String>,
+}
+
+impl TryFrom<&PathInfo> for NarInfo {
+    type Error = Error;
+
+    fn try_from(info: &PathInfo) -> Result<Self> {
+        let mut signatures = HashMap::new();
+        for signature in &info.signatures {
+            let signature = Signature::from_str(signature)?;
+            signatures.insert(signature.key_name, signature.signature);
+        }
+        Ok(NarInfo {
+            path: info.path.clone(),
+            nar_hash: NixHash::from_str(&info.nar_hash)?,
+            nar_size: info.nar_size,
+            file_hash: None,
+            file_size: None,
+            url: None,
+            compression: None,
+            deriver: info.deriver.as_deref().map(|deriver| deriver.strip_prefix("/nix/store/").unwrap_or(deriver).to_string()),
+            ca: info.ca.clone(),
+            references: info.references.iter().cloned().collect::<BTreeSet<_>>(),
+            signatures,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathInfos {
+    List(Vec<PathInfo>),
+    /// Invalid paths have no info.
+    Map(HashMap<String, Option<PathInfo>>),
+}
+
+struct Metadata {
+    modified: SystemTime,
+    /// By the hash part of the store path.
+    paths: Arc<HashMap<String, PathInfo>>,
+}
+
+/// Where the backend learns which paths are valid.
+pub enum Source {
+    /// JSON as printed by `nix path-info --json`, read again whenever it changed.
+    SideFile(PathBuf),
+    /// The database of the store, such as `/nix/var/nix/db/db.sqlite`.
+    Database(Arc<StoreDb>),
+}
+
+pub struct StoreBackend {
+    store_dir: PathBuf,
+    compression: Compression,
+    source: Source,
+    side_file: Mutex<Option<Metadata>>,
+    /// JSON listings by the hash part of the store path.
+    listings: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+}
+
+impl StoreBackend {
+    pub fn new<T: Into<PathBuf>>(store_dir: T, compression: Compression, source: Source) -> Self {
+        StoreBackend {
+            store_dir: store_dir.into(),
+            compression,
+            source,
+            side_file: Mutex::new(None),
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Describes a valid path, without url and compression.
+    async fn lookup(&self, id: &str) -> Result<Option<NarInfo>> {
+        match &self.source {
+            Source::SideFile(path) => self.side_file(path).await?.get(id).map(NarInfo::try_from).transpose(),
+            Source::Database(db) => {
+                let (db, id) = (db.clone(), id.to_string());
+                tokio::task::spawn_blocking(move || db.query_path(&id)).await.map_err(|_| Error::Backend)?
+            }
+        }
+    }
+
+    async fn side_file(&self, path: &PathBuf) -> Result<Arc<HashMap<String, PathInfo>>> {
+        let modified = tokio::fs::metadata(path).await?.modified()?;
+        if let Some(metadata) = &*self.side_file.lock().expect("not poisoned") {
+            if metadata.modified == modified {
+                return Ok(metadata.paths.clone());
+            }
+        }
+        let json = tokio::fs::read(path).await?;
+        let infos = serde_json::from_slice(&json).map_err(|e| {
+            warn!("invalid store metadata {}: {}", path.display(), e);
+            Error::Backend
+        })?;
+        let infos = match infos {
+            PathInfos::List(infos) => infos,
+            PathInfos::Map(infos) => infos
+                .into_iter()
+                .filter_map(|(path, info)| info.map(|info| PathInfo { path, ..info }))
+                .collect(),
+        };
+        let paths = Arc::new(
+            infos
+                .into_iter()
+                .map(|info| (path_id(&info.path).to_string(), info))
+                .collect::<HashMap<_, _>>(),
+        );
+        *self.side_file.lock().expect("not poisoned") = Some(Metadata { modified, paths: paths.clone() });
+        Ok(paths)
+    }
+
+    /// The directory entry of a valid path.
+    async fn store_path(&self, id: &str) -> Result<PathBuf> {
+        let nar_info = self.lookup(id).await?.ok_or(Error::NotFound)?;
+        let name = nar_info.path.rsplit('/').next().unwrap_or_default();
+        let path = self.store_dir.join(name);
+        match tokio::fs::symlink_metadata(&path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Error::NotFound),
+            metadata => metadata?,
+        };
+        Ok(path)
+    }
+
+    /// The JSON listing of a valid path, which takes archiving it the first time.
+    async fn listing(&self, id: &str) -> Result<Arc<Vec<u8>>> {
+        if let Some(json) = self.listings.lock().expect("not poisoned").get(id) {
+            return Ok(json.clone());
+        }
+        let path = self.store_path(id).await?;
+        let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+        let dump = async {
+            // The listing ends with the end of the NAR, which is when the writer is gone.
+            drop(nar::dump(&path, writer).await?);
+            Ok(())
+        };
+        let (_, listing) = tokio::try_join!(dump, listing::list_nar(reader))?;
+        let json = Arc::new(serde_json::to_vec(&listing).map_err(|_| Error::Backend)?);
+        let mut listings = self.listings.lock().expect("not poisoned");
+        if listings.len() >= MAX_LISTINGS {
+            // Any entry will do, they are all as cheap to generate again.
+            if let Some(evicted) = listings.keys().next().cloned() {
+                listings.remove(&evicted);
+            }
+        }
+        listings.insert(id.to_string(), json.clone());
+        Ok(json)
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for StoreBackend {
+    async fn read_nar(&self, url: &str) -> Result<NarResponder> {
+        let (id, extension) = url.split_at(url.find('.').ok_or(Error::NotFound)?);
+        if url == listing::listing_key(id) {
+            let json = self.listing(id).await?;
+            return Ok(NarResponder::Reader(Box::new(std::io::Cursor::new(json.to_vec()))));
+        }
+        let compression = [Compression::Xz, Compression::Bzip2, Compression::Gzip, Compression::Zstd, Compression::Plain]
+            .into_iter()
+            .find(|compression| compression.nar_extension() == extension)
+            .ok_or(Error::NotFound)?;
+        let path = self.store_path(id).await?;
+        let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+        tokio::spawn(async move {
+            // The download ends early when this fails, which Nix notices by the NAR hash.
+            if let Err(e) = nar::dump(&path, writer).await {
+                warn!("failed to archive {}: {}", path.display(), e);
+            }
+        });
+        Ok(NarResponder::Reader(compression.encoder(reader)))
+    }
+    async fn write_nar(&self, _url: &str, _reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+    async fn finish_nar(&self, _url: &str) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+    /// Evicted paths are only forgotten, they stay in the store.
+    async fn delete_nar(&self, _url: &str) -> Result<()> {
+        Ok(())
+    }
+    async fn abort_nar(&self, _url: &str) -> Result<()> {
+        Ok(())
+    }
+    async fn quarantine_nar(&self, _url: &str) -> Result<()> {
+        Ok(())
+    }
+    async fn list(&self, _area: Area) -> Result<Vec<ObjectInfo>> {
+        Ok(Vec::new())
+    }
+    async fn find_path(&self, id: &str) -> Result<Option<NarInfo>> {
+        Ok(self.lookup(id).await?.map(|nar_info| NarInfo {
+            url: Some(format!("nar/{}{}", id, self.compression.nar_extension())),
+            compression: Some(self.compression.clone()),
+            ..nar_info
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[rocket::async_test]
+    async fn fake_store() {
+        let root = std::env::temp_dir().join(format!("nyancache-store-{}", std::process::id()));
+        let store_dir = root.join("store");
+        std::fs::create_dir_all(store_dir.join("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello/bin")).unwrap();
+        std::fs::write(store_dir.join("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello/bin/hello"), "hello").unwrap();
+        let metadata = serde_json::json!({
+            "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello": {
+                "narHash": "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+                "narSize": 456,
+                "references": ["/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello"],
+                "deriver": "/nix/store/0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv",
+                "signatures": [],
+            },
+            "/nix/store/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-invalid": null,
+        });
+        std::fs::write(root.join("paths.json"), metadata.to_string()).unwrap();
+        let backend = StoreBackend::new(&store_dir, Compression::Plain, Source::SideFile(root.join("paths.json")));
+
+        let nar_info = backend.find_path("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0").await.unwrap().unwrap();
+        let mut nar = Vec::new();
+        let read = match backend.read_nar("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0.nar").await {
+            Ok(responder) => responder.into_reader().read_to_end(&mut nar).await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        let missing = backend.find_path("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz").await;
+        let mut json = Vec::new();
+        let listing = match backend.read_nar("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0.ls").await {
+            Ok(responder) => responder.into_reader().read_to_end(&mut json).await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        std::fs::remove_dir_all(&root).unwrap();
+        // Served from memory once the store is gone.
+        let cached = backend.listing("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0").await.unwrap();
+
+        assert_eq!(nar_info.url.as_deref(), Some("nar/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0.nar"));
+        assert_eq!(nar_info.deriver.as_deref(), Some("0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv"));
+        assert_eq!(nar_info.nar_hash.to_string(), "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s");
+        read.unwrap();
+        assert_eq!(nar.len() as u64, nar_info.nar_size);
+        assert!(missing.unwrap().is_none());
+        listing.unwrap();
+        let listing = serde_json::from_slice::<listing::Listing>(&json).unwrap();
+        assert!(listing.root.lookup(["bin", "hello"]).is_some());
+        assert_eq!(*cached, json);
+    }
+}
@@ -0,0 +1,171 @@
+// Rocket's route attribute re-exports a generated `uri!` macro for every handler, which
+// nothing outside of this module uses.
+#![allow(unused_imports)]
+
+use crate::backend::NarResponder;
+use crate::chunks;
+use crate::db::{db_run, DbConn};
+use crate::error::{Error, Result};
+use crate::html::{escape, page};
+use crate::listing::{self, Listing, ListingNode};
+use crate::models::PathQueries;
+use crate::State;
+
+use rocket::http::uri::{fmt::Path, Segments};
+use rocket::http::{Accept, ContentType, RawStr};
+use rocket::response::content::Html;
+use rocket::response::{Redirect, Responder, Response};
+use rocket::Request;
+use rocket::serde::json::Json;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![browse]
+}
+
+pub enum Browse {
+    Html(Html<String>),
+    Json(Json<ListingNode>),
+    File(NarResponder, ContentType),
+    Redirect(Redirect),
+}
+
+impl<'r> Responder<'r, 'r> for Browse {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'r> {
+        match self {
+            Browse::Html(html) => html.respond_to(req),
+            Browse::Json(json) => json.respond_to(req),
+            // Shield adds `X-Content-Type-Options: nosniff` to every response.
+            Browse::File(nar, content_type) => Response::build_from(nar.respond_to(req)?)
+                .header(content_type)
+                .raw_header("Content-Security-Policy", "sandbox")
+                .ok(),
+            Browse::Redirect(redirect) => redirect.respond_to(req),
+        }
+    }
+}
+
+/// Serves a file inside of a cached store path, or lists a directory as HTML or, if
+/// the client prefers it, as JSON.
+#[rocket::get("/<id>/<path..>")]
+async fn browse(
+    conn: DbConn,
+    id: &str,
+    path: Segments<'_, Path>,
+    accept: Option<&Accept>,
+    state: &rocket::State<Arc<State>>,
+) -> Result<Browse> {
+    // Names are looked up as they are, including dotfiles, which a `PathBuf` would refuse.
+    let components = path.collect::<Vec<_>>();
+    if components.contains(&"..") {
+        return Err(Error::NotFound);
+    }
+    let key = id.to_string();
+    let (db_path, _) = db_run!(conn, |c| c.load_path(&key))?.ok_or(Error::NotFound)?;
+    let json = listing::read_listing(&*state.backend, id).await?;
+    let listing = serde_json::from_slice::<Listing>(&json).map_err(|_| Error::Backend)?;
+    let node = listing.root.lookup(components.iter().copied()).ok_or(Error::NotFound)?;
+
+    match node {
+        ListingNode::Regular { size, executable, nar_offset } => {
+            let nar = chunks::open_nar_at(&conn, state.backend.clone(), &db_path, *nar_offset).await?;
+            // Files are served as they are, so that cached contents never run as a page of
+            // the cache.
+            let content_type = if *executable { ContentType::Binary } else { ContentType::Plain };
+            Ok(Browse::File(NarResponder::Reader(Box::new(nar.take(*size))), content_type))
+        }
+        ListingNode::Symlink { target } => match resolve(id, &components, target) {
+            Some(location) => Ok(Browse::Redirect(Redirect::to(location))),
+            None => Err(Error::NotFound),
+        },
+        ListingNode::Directory { .. } if accept.is_some_and(|accept| accept.preferred().is_json()) => {
+            Ok(Browse::Json(Json(node.clone())))
+        }
+        ListingNode::Directory { entries } => {
+            let mut body = format!("<h1>{}</h1>\n<table>\n", breadcrumbs(id, &db_path.path, &components));
+            body.push_str("<tr><th>Name</th><th>Type</th><th class=\"number\">Size</th></tr>\n");
+            for (name, entry) in entries {
+                let href = browse_url(id, components.iter().copied().chain(Some(name.as_str())));
+                let (kind, size) = match entry {
+                    ListingNode::Regular { size, executable: true, .. } => ("executable".to_string(), size.to_string()),
+                    ListingNode::Regular { size, .. } => ("file".to_string(), size.to_string()),
+                    ListingNode::Symlink { target } => (format!("symlink to {}", escape(target)), String::new()),
+                    ListingNode::Directory { .. } => ("directory".to_string(), String::new()),
+                };
+                body.push_str(&format!(
+                    "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td class=\"number\">{}</td></tr>\n",
+                    escape(&href),
+                    escape(name),
+                    kind,
+                    size
+                ));
+            }
+            body.push_str("</table>");
+            Ok(Browse::Html(page(&db_path.path, &body)))
+        }
+    }
+}
+
+/// The URL browsing a path inside of the store path with hash `id`.
+pub fn browse_url<'a, I: IntoIterator<Item = &'a str>>(id: &str, path: I) -> String {
+    let mut url = format!("/browse/{}", id);
+    for name in path {
+        url.push('/');
+        url.push_str(&RawStr::new(name).percent_encode().to_string());
+    }
+    url
+}
+
+/// The store path and every directory in it up to `components`, each linking to itself.
+fn breadcrumbs(id: &str, store_path: &str, components: &[&str]) -> String {
+    let mut html = format!("<a href=\"{}\">{}</a>", escape(&browse_url(id, None)), escape(store_path));
+    for (n, name) in components.iter().enumerate() {
+        let href = browse_url(id, components[..=n].iter().copied());
+        html.push_str(&format!("/<a href=\"{}\">{}</a>", escape(&href), escape(name)));
+    }
+    html
+}
+
+/// Where browsing a symlink leads: inside the same store path for relative targets, or
+/// into another store path for absolute ones. `None` if the target leaves the store.
+fn resolve(id: &str, link: &[&str], target: &str) -> Option<String> {
+    if let Some(target) = target.strip_prefix("/nix/store/") {
+        let mut components = target.split('/').filter(|c| !c.is_empty());
+        let (hash, _) = components.next()?.split_once('-')?;
+        return Some(browse_url(hash, components));
+    }
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut path = link[..link.len().saturating_sub(1)].to_vec();
+    for component in target.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                path.pop()?;
+            }
+            name => path.push(name),
+        }
+    }
+    Some(browse_url(id, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlinks() {
+        let id = "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0";
+        assert_eq!(resolve(id, &["lib", "libz.so"], "libz.so.1").unwrap(), format!("/browse/{}/lib/libz.so.1", id));
+        assert_eq!(resolve(id, &["bin", "sh"], "../libexec/./sh").unwrap(), format!("/browse/{}/libexec/sh", id));
+        assert_eq!(resolve(id, &["sh"], "../../bin/sh"), None);
+        assert_eq!(resolve(id, &["sh"], "/bin/sh"), None);
+        assert_eq!(
+            resolve(id, &["bin"], "/nix/store/0c0m0yl0z2fhsv8x7v0kgl2nvhfxnwm6-bash-5.1/bin").unwrap(),
+            "/browse/0c0m0yl0z2fhsv8x7v0kgl2nvhfxnwm6/bin"
+        );
+        assert_eq!(browse_url(id, ["a b", "c"]), format!("/browse/{}/a%20b/c", id));
+    }
+}
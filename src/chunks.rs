@@ -0,0 +1,487 @@
+use crate::backend::Backend;
+use crate::config::ChunkingConfig;
+use crate::db::{db_run, impl_for_connections, DbConn};
+use crate::error::{Error, Result};
+use crate::models::{backend_url, DbPath};
+use crate::nixutils::{Compression, HashType, Hasher};
+use crate::schema::chunks::{self, dsl::chunks as all_chunks};
+use crate::schema::object_chunks::{self, dsl::object_chunks as all_object_chunks};
+
+use diesel::sql_types::BigInt;
+use diesel::{Connection, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl};
+use diesel_derives::{Insertable, QueryableByName};
+use fastcdc::v2020::AsyncStreamCDC;
+use log::info;
+use rocket::futures::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Chunks claimed per transaction, which bounds the chunk data held in memory as well as
+/// the bind parameters of its statements.
+const BATCH_SIZE: usize = 64;
+
+#[derive(Debug, Insertable)]
+#[table_name = "chunks"]
+struct DbChunk {
+    hash: String,
+    size: i64,
+    refcount: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "object_chunks"]
+struct DbObjectChunk {
+    url: String,
+    seq: i64,
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, QueryableByName)]
+pub struct ChunkStats {
+    #[sql_type = "BigInt"]
+    pub chunks: i64,
+    /// Bytes of chunks kept in the backend.
+    #[sql_type = "BigInt"]
+    pub stored_bytes: i64,
+    /// Bytes of the NARs made up of these chunks.
+    #[sql_type = "BigInt"]
+    pub nar_bytes: i64,
+}
+
+impl ChunkStats {
+    /// How many bytes of NARs every stored byte serves.
+    pub fn dedup_ratio(&self) -> f64 {
+        self.nar_bytes as f64 / self.stored_bytes.max(1) as f64
+    }
+}
+
+/// Queries on `chunks` and `object_chunks`, which keep the chunk refcounts consistent.
+pub trait ChunkQueries {
+    /// The chunk hashes an object consists of, in order. Empty if it is stored whole.
+    fn manifest(&self, url: &str) -> QueryResult<Vec<String>>;
+    /// Like [`ChunkQueries::manifest`], along with the size of every chunk.
+    fn manifest_sizes(&self, url: &str) -> QueryResult<Vec<(String, i64)>>;
+    fn is_chunked(&self, url: &str) -> QueryResult<bool>;
+    /// The chunks of every chunked object along with their sizes, by object url.
+    fn manifests(&self) -> QueryResult<HashMap<String, Vec<(String, i64)>>>;
+    /// The hashes among `hashes` of chunks that are stored.
+    fn stored_chunks(&self, hashes: &[String]) -> QueryResult<HashSet<String>>;
+    /// Records chunks of an object as `(hash, size)` pairs, numbered from `first_seq`,
+    /// and takes a reference on each.
+    ///
+    /// Chunks that are not stored yet are only recorded if they are in `written`, that is
+    /// the caller wrote them to the backend already. Otherwise nothing is recorded, and
+    /// the hashes of those chunks are returned, which the caller has to write before
+    /// claiming the batch again.
+    fn claim_chunks(
+        &self,
+        url: &str,
+        first_seq: i64,
+        batch: &[(String, i64)],
+        written: &HashSet<String>,
+    ) -> QueryResult<Vec<String>>;
+    /// Forgets the chunks of an object. Returns the hashes of chunks no object uses any
+    /// more, which the caller then has to remove from the backend.
+    fn release_manifest(&self, url: &str) -> QueryResult<Vec<String>>;
+    fn chunk_hashes(&self) -> QueryResult<Vec<String>>;
+    fn chunk_stats(&self) -> QueryResult<ChunkStats>;
+}
+
+impl_for_connections!(ChunkQueries {
+    fn manifest(&self, url: &str) -> QueryResult<Vec<String>> {
+        all_object_chunks
+            .select(object_chunks::hash)
+            .filter(object_chunks::url.eq(url))
+            .order(object_chunks::seq)
+            .load(self)
+    }
+
+    fn manifest_sizes(&self, url: &str) -> QueryResult<Vec<(String, i64)>> {
+        all_object_chunks
+            .inner_join(all_chunks.on(chunks::hash.eq(object_chunks::hash)))
+            .select((object_chunks::hash, chunks::size))
+            .filter(object_chunks::url.eq(url))
+            .order(object_chunks::seq)
+            .load(self)
+    }
+
+    fn is_chunked(&self, url: &str) -> QueryResult<bool> {
+        let first = all_object_chunks
+            .select(object_chunks::seq)
+            .filter(object_chunks::url.eq(url))
+            .first::<i64>(self)
+            .optional()?;
+        Ok(first.is_some())
+    }
+
+    fn manifests(&self) -> QueryResult<HashMap<String, Vec<(String, i64)>>> {
+        let rows = all_object_chunks
+            .inner_join(all_chunks.on(chunks::hash.eq(object_chunks::hash)))
+            .select((object_chunks::url, object_chunks::hash, chunks::size))
+            .order((object_chunks::url, object_chunks::seq))
+            .load::<(String, String, i64)>(self)?;
+        let mut manifests = HashMap::<_, Vec<_>>::new();
+        for (url, hash, size) in rows {
+            manifests.entry(url).or_default().push((hash, size));
+        }
+        Ok(manifests)
+    }
+
+    fn stored_chunks(&self, hashes: &[String]) -> QueryResult<HashSet<String>> {
+        let stored = all_chunks
+            .select(chunks::hash)
+            .filter(chunks::hash.eq_any(hashes))
+            .load::<String>(self)?;
+        Ok(stored.into_iter().collect())
+    }
+
+    fn claim_chunks(
+        &self,
+        url: &str,
+        first_seq: i64,
+        batch: &[(String, i64)],
+        written: &HashSet<String>,
+    ) -> QueryResult<Vec<String>> {
+        let mut unwritten = Vec::new();
+        let claimed = self.transaction(|| {
+            let rows = batch
+                .iter()
+                .enumerate()
+                .map(|(seq, (hash, _))| DbObjectChunk {
+                    url: url.to_string(),
+                    seq: first_seq + seq as i64,
+                    hash: hash.clone(),
+                })
+                .collect::<Vec<_>>();
+            diesel::insert_into(all_object_chunks).values(&rows).execute(self)?;
+            for (hash, size) in batch {
+                // Existing chunks stay locked until the end of the transaction, so they
+                // cannot be released in between.
+                let updated = diesel::update(all_chunks.find(hash))
+                    .set(chunks::refcount.eq(chunks::refcount + 1))
+                    .execute(self)?;
+                if updated > 0 {
+                    continue;
+                }
+                // Released since the caller looked, and maybe deleted from the backend.
+                if !written.contains(hash) {
+                    unwritten.push(hash.clone());
+                    continue;
+                }
+                let chunk = DbChunk {
+                    hash: hash.clone(),
+                    size: *size,
+                    refcount: 1,
+                };
+                diesel::insert_into(all_chunks).values(&chunk).execute(self)?;
+            }
+            match unwritten.is_empty() {
+                true => Ok(()),
+                false => Err(diesel::result::Error::RollbackTransaction),
+            }
+        });
+        match claimed {
+            Err(diesel::result::Error::RollbackTransaction) => Ok(unwritten),
+            claimed => claimed.map(|()| Vec::new()),
+        }
+    }
+
+    fn release_manifest(&self, url: &str) -> QueryResult<Vec<String>> {
+        self.transaction(|| {
+            let hashes = self.manifest(url)?;
+            diesel::delete(all_object_chunks.filter(object_chunks::url.eq(url))).execute(self)?;
+            let mut unused = Vec::new();
+            for hash in &hashes {
+                diesel::update(all_chunks.find(hash))
+                    .set(chunks::refcount.eq(chunks::refcount - 1))
+                    .execute(self)?;
+            }
+            for hash in hashes.into_iter().collect::<HashSet<_>>() {
+                if diesel::delete(all_chunks.find(&hash).filter(chunks::refcount.le(0))).execute(self)? > 0 {
+                    unused.push(hash);
+                }
+            }
+            Ok(unused)
+        })
+    }
+
+    fn chunk_hashes(&self) -> QueryResult<Vec<String>> {
+        all_chunks.select(chunks::hash).load(self)
+    }
+
+    fn chunk_stats(&self) -> QueryResult<ChunkStats> {
+        diesel::sql_query(
+            "SELECT COUNT(*) AS chunks,
+                CAST(COALESCE(SUM(size), 0) AS BIGINT) AS stored_bytes,
+                CAST(COALESCE(SUM(size * refcount), 0) AS BIGINT) AS nar_bytes
+            FROM chunks",
+        )
+        .get_result(self)
+    }
+});
+
+/// The backend key of a chunk, fanned out into directories by the first characters of
+/// the hash.
+pub fn chunk_key(hash: &str) -> String {
+    let digest = hash.rsplit(':').next().unwrap_or(hash);
+    format!("chunks/{}/{}", &digest[..2.min(digest.len())], digest)
+}
+
+/// Splits the finished object at `url` into chunks and stores those that are new. The
+/// object itself stays until the caller deletes it, once a path refers to the chunks.
+///
+/// Chunks are written to the backend before they are recorded, so that a failure in
+/// between leaves orphaned chunks rather than recorded ones without data. On failure, the
+/// chunks recorded so far are released again.
+pub async fn store_chunked(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    config: &ChunkingConfig,
+    url: &str,
+    compression: &Compression,
+) -> Result<()> {
+    match split_object(conn, backend, config, url, compression).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            release_chunks(conn, backend, url).await?;
+            Err(e)
+        }
+    }
+}
+
+async fn split_object(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    config: &ChunkingConfig,
+    url: &str,
+    compression: &Compression,
+) -> Result<()> {
+    let key = backend_url(url).ok_or(Error::Upload)?;
+    let nar = compression.decoder(backend.read_nar(key).await?.into_reader());
+    let mut chunker = AsyncStreamCDC::new(nar, config.min_size, config.avg_size, config.max_size);
+    let mut chunks = Box::pin(chunker.as_stream()).try_chunks(BATCH_SIZE);
+
+    let (mut count, mut nar_bytes, mut new_bytes) = (0, 0, 0);
+    while let Some(batch) = chunks.next().await {
+        let batch = batch.map_err(|e| match e.1 {
+            fastcdc::v2020::Error::IoError(e) => Error::Io(e),
+            _ => Error::Upload,
+        })?;
+        let mut data = HashMap::new();
+        let mut manifest = Vec::new();
+        for chunk in batch {
+            let mut hasher = Hasher::new(HashType::Sha256)?;
+            hasher.update(&chunk.data);
+            let hash = hasher.finish().to_string();
+            nar_bytes += chunk.length;
+            manifest.push((hash.clone(), chunk.length as i64));
+            data.insert(hash, chunk.data);
+        }
+
+        let hashes = data.keys().cloned().collect::<Vec<_>>();
+        let stored = db_run!(conn, |c| c.stored_chunks(&hashes))?;
+        // Claimed once, and again with the chunks that were released in the meantime.
+        let mut unwritten = Some(data.keys().filter(|hash| !stored.contains(*hash)).cloned().collect::<Vec<_>>());
+        let mut written = HashSet::new();
+        let first_seq = count as i64;
+        count += manifest.len();
+        while let Some(hashes) = unwritten.take() {
+            for hash in hashes {
+                let chunk = &data[&hash];
+                let chunk_key = chunk_key(&hash);
+                backend.write_nar(&chunk_key, &mut chunk.as_slice()).await?;
+                backend.finish_nar(&chunk_key).await?;
+                new_bytes += chunk.len();
+                written.insert(hash);
+            }
+            let (object_url, manifest, written) = (url.to_string(), manifest.clone(), written.clone());
+            let vanished = db_run!(conn, |c| c.claim_chunks(&object_url, first_seq, &manifest, &written))?;
+            unwritten = Some(vanished).filter(|vanished| !vanished.is_empty());
+        }
+    }
+
+    info!("chunked {} into {} chunks, {} of {} bytes new", url, count, new_bytes, nar_bytes);
+    Ok(())
+}
+
+/// Reads the decompressed NAR of a chunked object.
+pub fn reassemble<'a, B>(backend: B, manifest: Vec<String>) -> Box<dyn AsyncRead + Send + Unpin + 'a>
+where
+    B: Deref + Clone + Send + Sync + 'a,
+    B::Target: Backend + Send + Sync,
+{
+    let chunks = rocket::futures::stream::iter(manifest)
+        .then(move |hash| {
+            let backend = backend.clone();
+            async move {
+                let chunk = backend.read_nar(&chunk_key(&hash)).await.map_err(std::io::Error::other)?;
+                Ok::<_, std::io::Error>(ReaderStream::new(chunk.into_reader()))
+            }
+        })
+        .try_flatten();
+    Box::new(StreamReader::new(Box::pin(chunks)))
+}
+
+/// Opens the decompressed NAR of a path, whether its object is stored whole or chunked.
+pub async fn open_nar<'a, B>(conn: &DbConn, backend: B, db_path: &DbPath) -> Result<Box<dyn AsyncRead + Send + Unpin + 'a>>
+where
+    B: Deref + Clone + Send + Sync + 'a,
+    B::Target: Backend + Send + Sync,
+{
+    let object_url = db_path.url.clone().ok_or(Error::NotFound)?;
+    let manifest = db_run!(conn, |c| c.manifest(&object_url))?;
+    if !manifest.is_empty() {
+        return Ok(reassemble(backend, manifest));
+    }
+    let key = db_path.backend_url().ok_or(Error::NotFound)?;
+    Ok(db_path.compression()?.decoder(backend.read_nar(key).await?.into_reader()))
+}
+
+/// Opens the decompressed NAR of a path at `offset`. Chunked objects are read from the
+/// chunk containing it, others still have to be decompressed up to it.
+pub async fn open_nar_at<'a, B>(
+    conn: &DbConn,
+    backend: B,
+    db_path: &DbPath,
+    offset: u64,
+) -> Result<Box<dyn AsyncRead + Send + Unpin + 'a>>
+where
+    B: Deref + Clone + Send + Sync + 'a,
+    B::Target: Backend + Send + Sync,
+{
+    let object_url = db_path.url.clone().ok_or(Error::NotFound)?;
+    let manifest = db_run!(conn, |c| c.manifest_sizes(&object_url))?;
+    let (mut nar, start) = match manifest.is_empty() {
+        true => {
+            let key = db_path.backend_url().ok_or(Error::NotFound)?;
+            (db_path.compression()?.decoder(backend.read_nar(key).await?.into_reader()), 0)
+        }
+        false => {
+            let mut start = 0;
+            let first = manifest
+                .iter()
+                .position(|(_, size)| match start + *size as u64 > offset {
+                    true => true,
+                    false => {
+                        start += *size as u64;
+                        false
+                    }
+                })
+                .unwrap_or(manifest.len());
+            let hashes = manifest.into_iter().skip(first).map(|(hash, _)| hash).collect();
+            (reassemble(backend, hashes), start)
+        }
+    };
+    let skip = offset - start;
+    if tokio::io::copy(&mut (&mut nar).take(skip), &mut tokio::io::sink()).await? != skip {
+        return Err(Error::UnexpectedEof);
+    }
+    Ok(nar)
+}
+
+/// Removes an object nothing refers to any more from the backend, along with the chunks
+/// only it used.
+pub async fn delete_object(conn: &DbConn, backend: &(dyn Backend + Send + Sync), url: &str) -> Result<()> {
+    if !release_chunks(conn, backend, url).await? {
+        if let Some(key) = backend_url(url) {
+            backend.delete_nar(key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the chunks of an object and deletes those no other object uses. Returns
+/// whether the object was chunked.
+pub async fn release_chunks(conn: &DbConn, backend: &(dyn Backend + Send + Sync), url: &str) -> Result<bool> {
+    let object_url = url.to_string();
+    let unused = db_run!(conn, |c| {
+        match c.is_chunked(&object_url)? {
+            true => c.release_manifest(&object_url).map(Some),
+            false => Ok::<_, diesel::result::Error>(None),
+        }
+    })?;
+    match unused {
+        Some(hashes) => {
+            for hash in hashes {
+                backend.delete_nar(&chunk_key(&hash)).await?;
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Area, NarResponder, ObjectInfo};
+    use crate::tests::{client, file_nar, test_root};
+    use crate::State;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Fails to write chunks while `fail` is set, like a backend that went away.
+    struct FailingBackend {
+        inner: Arc<dyn Backend + Send + Sync>,
+        fail: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for FailingBackend {
+        async fn read_nar(&self, url: &str) -> Result<NarResponder> {
+            self.inner.read_nar(url).await
+        }
+        async fn write_nar(&self, url: &str, reader: &mut (dyn AsyncRead + Send + Unpin)) -> Result<()> {
+            if self.fail.load(Ordering::SeqCst) && url.starts_with("chunks/") {
+                return Err(Error::Backend);
+            }
+            self.inner.write_nar(url, reader).await
+        }
+        async fn finish_nar(&self, url: &str) -> Result<()> {
+            self.inner.finish_nar(url).await
+        }
+        async fn delete_nar(&self, url: &str) -> Result<()> {
+            self.inner.delete_nar(url).await
+        }
+        async fn abort_nar(&self, url: &str) -> Result<()> {
+            self.inner.abort_nar(url).await
+        }
+        async fn quarantine_nar(&self, url: &str) -> Result<()> {
+            self.inner.quarantine_nar(url).await
+        }
+        async fn list(&self, area: Area) -> Result<Vec<ObjectInfo>> {
+            self.inner.list(area).await
+        }
+    }
+
+    #[rocket::async_test]
+    async fn failed_chunk_writes() {
+        let client = client("chunks-failed-write", true).await;
+        let conn = DbConn::get_one(client.rocket()).await.unwrap();
+        let state = client.rocket().state::<Arc<State>>().unwrap();
+        let backend = FailingBackend { inner: state.backend.clone(), fail: AtomicBool::new(true) };
+        let contents = (0..5000).map(|n| (n * 7919 % 251) as u8).collect::<Vec<_>>();
+        let nar = file_nar(&contents).await;
+        for key in ["first.nar", "second.nar"] {
+            backend.write_nar(key, &mut nar.as_slice()).await.unwrap();
+            backend.finish_nar(key).await.unwrap();
+        }
+
+        // Fails like a crash would, without releasing anything.
+        let split = split_object(&conn, &backend, &state.chunking, "nar/first.nar", &Compression::Plain);
+        assert!(split.await.is_err());
+        backend.fail.store(false, Ordering::SeqCst);
+        split_object(&conn, &backend, &state.chunking, "nar/second.nar", &Compression::Plain).await.unwrap();
+
+        let manifest = db_run!(conn, |c| c.manifest("nar/second.nar")).unwrap();
+        assert!(manifest.len() > 1);
+        let mut read = Vec::new();
+        reassemble(state.backend.clone(), manifest).read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, nar);
+        std::fs::remove_dir_all(test_root("chunks-failed-write")).unwrap();
+    }
+}
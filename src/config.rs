@@ -0,0 +1,246 @@
+use crate::backend::{local::{LocalBackend, Watermarks}, store::{Source, StoreBackend}, Backend};
+use crate::db::DatabaseKind;
+use crate::error::{Error, Result};
+use crate::gc::policy::PolicyKind;
+use crate::nixutils::store_db::StoreDb;
+use crate::nixutils::Compression;
+use rocket::data::ByteUnit;
+use s3::creds::Credentials;
+use s3::Bucket;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub database: DatabaseKind,
+    pub backend: BackendConfig,
+    pub gc: GcConfig,
+    /// Bearer token required by administrative endpoints, which are disabled without it.
+    pub admin_token: Option<String>,
+    /// File to append every narinfo and NAR request to, for `nyancache simulate`.
+    pub access_log: Option<PathBuf>,
+    pub chunking: ChunkingConfig,
+    /// How NARs that nyancache packs itself, such as imported ones, are compressed.
+    pub compression: Compression,
+}
+
+/// Content-defined chunking of uploaded NARs, so that NARs sharing most of their
+/// contents share most of their storage. Chunk sizes are in bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    pub enabled: bool,
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            enabled: false,
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    /// Whether the sizes are within what the chunker supports.
+    pub fn is_valid(&self) -> bool {
+        use fastcdc::v2020::{AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN};
+        (MINIMUM_MIN..=MINIMUM_MAX).contains(&self.min_size)
+            && (AVERAGE_MIN..=AVERAGE_MAX).contains(&self.avg_size)
+            && (MAXIMUM_MIN..=MAXIMUM_MAX).contains(&self.max_size)
+            && self.min_size <= self.avg_size
+            && self.avg_size <= self.max_size
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GcConfig {
+    /// Evict least recently used paths while the cache is larger than this. Without it,
+    /// nothing is ever collected.
+    pub max_size: Option<ByteUnit>,
+    /// Which paths go first when enforcing `max_size`.
+    pub policy: PolicyKind,
+    /// Evict paths that were not accessed for this long.
+    #[serde(deserialize_with = "optional_duration")]
+    pub max_idle: Option<Duration>,
+    /// Evict paths that were uploaded longer ago than this.
+    #[serde(deserialize_with = "optional_duration")]
+    pub max_age: Option<Duration>,
+    /// Time between collections while the server is running.
+    #[serde(deserialize_with = "duration")]
+    pub interval: Duration,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            max_size: None,
+            policy: PolicyKind::default(),
+            max_idle: None,
+            max_age: None,
+            interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Config {
+    /// Whether the garbage collector has anything to enforce.
+    pub fn gc_enabled(&self) -> bool {
+        self.gc.is_enabled() || self.backend.watermarks().is_some()
+    }
+}
+
+impl GcConfig {
+    /// Whether any retention limit is configured.
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_idle.is_some() || self.max_age.is_some()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+    Local {
+        tmp_dir: PathBuf,
+        data_dir: PathBuf,
+        quarantine_dir: PathBuf,
+        /// Evict paths when the disk runs full, on top of the limits in `gc`.
+        watermarks: Option<Watermarks>,
+    },
+    S3 {
+        bucket: String,
+        region: String,
+    },
+    /// Serves the paths of a local store, which are listed either in `metadata` as printed
+    /// by `nix path-info --json --sigs --all`, or in the Nix database `db`.
+    Store {
+        store_dir: PathBuf,
+        metadata: Option<PathBuf>,
+        db: Option<PathBuf>,
+        /// The store directory recorded in `db`, which differs from `store_dir` for
+        /// stores that were built under another root. Paths are served under `/nix/store`.
+        #[serde(default = "default_store_prefix")]
+        store_prefix: String,
+        /// How generated NARs are compressed.
+        #[serde(default)]
+        compression: Compression,
+    },
+}
+
+fn default_store_prefix() -> String {
+    "/nix/store".to_string()
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig::Local {
+            tmp_dir: "tmp".into(),
+            data_dir: "data".into(),
+            quarantine_dir: "quarantine".into(),
+            watermarks: None,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn watermarks(&self) -> Option<&Watermarks> {
+        match self {
+            BackendConfig::Local { watermarks, .. } => watermarks.as_ref(),
+            BackendConfig::S3 { .. } | BackendConfig::Store { .. } => None,
+        }
+    }
+
+    pub fn build(&self) -> Result<Box<dyn Backend + Send + Sync>> {
+        Ok(match self {
+            BackendConfig::Local { tmp_dir, data_dir, quarantine_dir, watermarks } => {
+                if let Some(watermarks) = watermarks {
+                    if watermarks.critical > watermarks.low || watermarks.low >= watermarks.high {
+                        return Err(Error::Config("watermarks must be ordered critical <= low < high"));
+                    }
+                }
+                Box::new(LocalBackend::new(tmp_dir, data_dir, quarantine_dir).with_watermarks(*watermarks))
+            }
+            BackendConfig::S3 { bucket, region } => {
+                let region = region.parse().map_err(|_| Error::Backend)?;
+                let credentials = Credentials::default().map_err(|_| Error::Backend)?;
+                Box::new(Bucket::new(bucket, region, credentials).map_err(|_| Error::Backend)?)
+            }
+            BackendConfig::Store { store_dir, metadata, db, store_prefix, compression } => {
+                let source = match (metadata, db) {
+                    (Some(metadata), None) => Source::SideFile(metadata.clone()),
+                    (None, Some(db)) => Source::Database(Arc::new(StoreDb::open(db, store_prefix)?)),
+                    (Some(_), Some(_)) => return Err(Error::Config("the store backend takes either metadata or db, not both")),
+                    (None, None) => return Err(Error::Config("the store backend needs either metadata or db")),
+                };
+                Box::new(StoreBackend::new(store_dir, compression.clone(), source))
+            }
+        })
+    }
+}
+
+/// Deserializes a [`ByteUnit`] from input that serde has buffered, as inside the tagged
+/// [`BackendConfig`], where the implementation of `ByteUnit` rejects strings.
+pub fn byte_unit<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<ByteUnit, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Int(u64),
+        Str(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Int(n) => Ok(n.into()),
+        Raw::Str(s) => s.parse().map_err(|_| D::Error::custom(format!("invalid byte unit {:?}", s))),
+    }
+}
+
+/// A duration like `30days`, as parsed by humantime, or a number of seconds.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Secs(u64),
+    Human(String),
+}
+
+impl RawDuration {
+    fn parse<E: serde::de::Error>(self) -> std::result::Result<Duration, E> {
+        match self {
+            RawDuration::Secs(secs) => Ok(Duration::from_secs(secs)),
+            RawDuration::Human(s) => humantime::parse_duration(&s).map_err(|e| E::custom(format!("invalid duration {:?}: {}", s, e))),
+        }
+    }
+}
+
+pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    RawDuration::deserialize(deserializer)?.parse()
+}
+
+pub fn optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error> {
+    Option::<RawDuration>::deserialize(deserializer)?.map(RawDuration::parse).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::{Format, Toml};
+    use rocket::figment::Figment;
+
+    #[test]
+    fn gc_durations() {
+        let toml = "max_idle = \"30days\"\nmax_age = 7776000\n";
+        let config = Figment::from(Toml::string(toml)).extract::<GcConfig>().unwrap();
+        assert_eq!(config.max_idle, Some(Duration::from_secs(30 * 24 * 3600)));
+        assert_eq!(config.max_age, Some(Duration::from_secs(90 * 24 * 3600)));
+        assert_eq!(config.interval, Duration::from_secs(3600));
+        assert!(Figment::from(Toml::string("interval = \"soon\"")).extract::<GcConfig>().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use crate::config::Config;
+use crate::migrations;
+
+use log::error;
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::{Build, Phase, Request, Rocket};
+use rocket_sync_db_pools::{database, diesel as rocket_diesel};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseKind {
+    #[default]
+    Sqlite,
+    Postgres,
+}
+
+#[database("sqlite_nyancache")]
+pub struct SqliteDb(rocket_diesel::SqliteConnection);
+
+#[database("postgres_nyancache")]
+pub struct PgDb(rocket_diesel::PgConnection);
+
+/// A connection to whichever database was configured.
+///
+/// Queries are run through [`db_run!`], which compiles them once for every backend.
+pub enum DbConn {
+    Sqlite(SqliteDb),
+    Postgres(PgDb),
+}
+
+/// Runs a blocking closure on the connection, like `run` on the pool types does.
+macro_rules! db_run {
+    ($conn:expr, |$c:ident| $body:expr) => {
+        match &$conn {
+            $crate::db::DbConn::Sqlite(conn) => conn.run(move |$c| $body).await,
+            $crate::db::DbConn::Postgres(conn) => conn.run(move |$c| $body).await,
+        }
+    };
+}
+pub(crate) use db_run;
+
+/// Implements a trait for every supported connection type, compiling the body once per
+/// backend.
+macro_rules! impl_for_connections {
+    ($trait:ident { $($body:tt)* }) => {
+        impl $trait for diesel::sqlite::SqliteConnection { $($body)* }
+        impl $trait for diesel::pg::PgConnection { $($body)* }
+    };
+}
+pub(crate) use impl_for_connections;
+
+/// SQL syntax that differs between the supported databases, for hand-written queries.
+pub trait Dialect {
+    /// The placeholder for the `n`th bind parameter, counting from 1.
+    fn bind_param(n: usize) -> String;
+}
+
+impl Dialect for diesel::sqlite::SqliteConnection {
+    fn bind_param(_n: usize) -> String {
+        "?".to_string()
+    }
+}
+
+impl Dialect for diesel::pg::PgConnection {
+    fn bind_param(n: usize) -> String {
+        format!("${}", n)
+    }
+}
+
+impl DbConn {
+    /// Sets up the pool for the configured database and applies pending migrations.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::try_on_ignite("Database", |rocket| async {
+            let kind = match rocket.figment().extract::<Config>() {
+                Ok(config) => config.database,
+                Err(e) => {
+                    error!("invalid configuration: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let rocket = match kind {
+                DatabaseKind::Sqlite => rocket.attach(SqliteDb::fairing()),
+                DatabaseKind::Postgres => rocket.attach(PgDb::fairing()),
+            };
+            Ok(rocket
+                .manage(kind)
+                .attach(AdHoc::try_on_ignite("Database Migrations", migrate)))
+        })
+    }
+
+    pub async fn get_one<P: Phase>(rocket: &Rocket<P>) -> Option<Self> {
+        match rocket.state::<DatabaseKind>()? {
+            DatabaseKind::Sqlite => SqliteDb::get_one(rocket).await.map(DbConn::Sqlite),
+            DatabaseKind::Postgres => PgDb::get_one(rocket).await.map(DbConn::Postgres),
+        }
+    }
+}
+
+async fn migrate(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+    let conn = match DbConn::get_one(&rocket).await {
+        Some(conn) => conn,
+        None => return Err(rocket),
+    };
+    match db_run!(conn, |c| migrations::run(c)) {
+        Ok(()) => Ok(rocket),
+        Err(e) => {
+            error!("failed to migrate database: {}", e);
+            Err(rocket)
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbConn {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        match req.rocket().state::<DatabaseKind>() {
+            Some(DatabaseKind::Sqlite) => SqliteDb::from_request(req).await.map(DbConn::Sqlite),
+            Some(DatabaseKind::Postgres) => PgDb::from_request(req).await.map(DbConn::Postgres),
+            None => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunks::ChunkQueries;
+    use crate::error::Error;
+    use crate::gc::GcQueries;
+    use crate::migrations::EmbeddedMigrations;
+    use crate::models::{Access, DbPath, DbPin, PathQueries};
+    use crate::schema::paths::dsl::paths;
+    use crate::schema::paths::id as db_id;
+
+    use assert_matches::assert_matches;
+    use diesel::pg::PgConnection;
+    use diesel::sqlite::SqliteConnection;
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use diesel_migrations::MigrationConnection;
+    use std::collections::{BTreeSet, HashMap, HashSet};
+
+    fn test_path(id: &str) -> DbPath {
+        DbPath {
+            id: id.to_string(),
+            path: format!("/nix/store/{}-test", id),
+            nar_size: 1000,
+            file_size: Some(100),
+            ..Default::default()
+        }
+    }
+
+    macro_rules! check_paths_roundtrip {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let db_path = DbPath {
+                id: "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0".to_string(),
+                path: "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-cuda".to_string(),
+                nar_size: 8589934592,
+                file_size: Some(6442450944),
+                url: Some("nar/foo.nar.xz".to_string()),
+                ..Default::default()
+            };
+            conn.insert_path(&db_path, &BTreeSet::new()).unwrap();
+            let loaded = paths
+                .filter(db_id.eq(&db_path.id))
+                .first::<DbPath>(&conn)
+                .unwrap();
+            assert_eq!(loaded.nar_size, db_path.nar_size);
+            assert_eq!(loaded.file_size, db_path.file_size);
+            assert_eq!(loaded.url, db_path.url);
+
+            let access = |last_accessed, hits| Access { last_accessed, hits };
+            let accesses = HashMap::from([(db_path.id.clone(), access(100, 2)), ("deleted".to_string(), access(100, 1))]);
+            conn.record_accesses(&accesses).unwrap();
+            conn.record_accesses(&HashMap::from([(db_path.id.clone(), access(200, 1))])).unwrap();
+            let (loaded, _) = conn.load_path(&db_path.id).unwrap().unwrap();
+            assert_eq!((loaded.last_accessed, loaded.hit_count), (Some(200), 3));
+        }};
+    }
+
+    macro_rules! check_closure {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let refs = |ids: &[&str]| ids.iter().map(|id| format!("/nix/store/{}-test", id)).collect::<BTreeSet<_>>();
+            conn.insert_path(&test_path("a"), &refs(&["a", "b"])).unwrap();
+            conn.insert_path(&test_path("b"), &refs(&["c"])).unwrap();
+            conn.insert_path(&test_path("c"), &refs(&["d"])).unwrap();
+            conn.insert_path(&test_path("e"), &refs(&["b"])).unwrap();
+
+            let mut closure = conn.closure("a").unwrap().into_iter().map(|p| p.id).collect::<Vec<_>>();
+            closure.sort();
+            assert_eq!(closure, vec!["a", "b", "c"]);
+            assert_eq!(conn.missing_references("a").unwrap(), vec!["/nix/store/d-test"]);
+            let ids = ["a", "d", "c", "x"].iter().map(|id| id.to_string()).collect::<Vec<_>>();
+            assert_eq!(conn.missing_paths(&ids).unwrap(), vec!["d", "x"]);
+            let found = conn.search_paths("b-t", 10).unwrap().into_iter().map(|p| p.id).collect::<Vec<_>>();
+            assert_eq!(found, vec!["b"]);
+            assert_eq!(conn.search_paths("B-T", 10).unwrap().len(), 1);
+            assert_eq!(conn.search_paths("-test", 2).unwrap().len(), 2);
+            assert!(conn.search_paths("%", 10).unwrap().is_empty());
+            let usage = conn.usage().unwrap();
+            assert_eq!((usage.paths, usage.nar_bytes, usage.file_bytes), (4, 4000, 400));
+
+            let mut referrers = conn.referrers("b").unwrap().into_iter().map(|p| p.id).collect::<Vec<_>>();
+            referrers.sort();
+            assert_eq!(referrers, vec!["a", "e"]);
+
+            conn.delete_path("e").unwrap();
+            assert_eq!(conn.referrers("b").unwrap().len(), 1);
+            let (_, references) = conn.load_path("a").unwrap().unwrap();
+            assert_eq!(references.len(), 2);
+        }};
+    }
+
+    macro_rules! check_pins {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let refs = |ids: &[&str]| ids.iter().map(|id| format!("/nix/store/{}-test", id)).collect::<BTreeSet<_>>();
+            conn.insert_path(&test_path("a"), &refs(&["b"])).unwrap();
+            conn.insert_path(&test_path("b"), &refs(&["c"])).unwrap();
+            conn.insert_path(&test_path("c"), &refs(&[])).unwrap();
+            conn.insert_path(&test_path("d"), &refs(&["c"])).unwrap();
+            let pin = |id: &str, expires| DbPin { id: id.to_string(), created: 0, expires, note: None };
+
+            conn.pin(&pin("b", None)).unwrap();
+            conn.pin(&pin("d", Some(100))).unwrap();
+            let mut pinned = conn.pinned_ids(50).unwrap().into_iter().collect::<Vec<_>>();
+            pinned.sort();
+            assert_eq!(pinned, vec!["b", "c", "d"]);
+
+            let mut pinned = conn.pinned_ids(150).unwrap().into_iter().collect::<Vec<_>>();
+            pinned.sort();
+            assert_eq!(pinned, vec!["b", "c"]);
+
+            conn.pin(&pin("b", Some(0))).unwrap();
+            assert_eq!(conn.pins().unwrap().len(), 2);
+            assert!(conn.pinned_ids(150).unwrap().is_empty());
+            assert_eq!(conn.unpin("d").unwrap(), 1);
+            conn.delete_path("b").unwrap();
+            assert!(conn.pins().unwrap().is_empty());
+        }};
+    }
+
+    macro_rules! check_objects {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let shared = |id: &str| DbPath {
+                url: Some("nar/shared.nar.xz".to_string()),
+                file_hash: Some("sha256:shared".to_string()),
+                ..test_path(id)
+            };
+            conn.insert_path(&shared("a"), &BTreeSet::new()).unwrap();
+            conn.insert_path(&shared("b"), &BTreeSet::new()).unwrap();
+            assert_eq!(conn.find_object("sha256:shared").unwrap().as_deref(), Some("nar/shared.nar.xz"));
+            assert_eq!(conn.find_object("sha256:other").unwrap(), None);
+            assert!(conn.has_object("nar/shared.nar.xz").unwrap());
+
+            // Uploading a path again only merges its signatures.
+            let resigned = DbPath { sigs: "k-1:AAAA".to_string(), ..shared("a") };
+            assert!(!conn.upsert_path(&resigned, &BTreeSet::new(), true).unwrap());
+            assert_eq!(conn.load_path("a").unwrap().unwrap().0.sigs, "k-1:AAAA");
+            // Unless its contents differ, which would move the signatures onto other contents.
+            let changed = DbPath { sigs: "k-2:BBBB".to_string(), nar_hash: "sha256:other".to_string(), ..shared("a") };
+            assert_matches!(conn.upsert_path(&changed, &BTreeSet::new(), true), Err(Error::InvalidUpload(_)));
+            let referencing = DbPath { sigs: "k-2:BBBB".to_string(), ..shared("a") };
+            let references = ["/nix/store/b-test".to_string()].into_iter().collect();
+            assert_matches!(conn.upsert_path(&referencing, &references, true), Err(Error::InvalidUpload(_)));
+            assert_eq!(conn.load_path("a").unwrap().unwrap().0.sigs, "k-1:AAAA");
+
+            assert_eq!(conn.delete_path("a").unwrap(), None);
+            assert_eq!(conn.delete_path("b").unwrap().as_deref(), Some("nar/shared.nar.xz"));
+            assert_eq!(conn.find_object("sha256:shared").unwrap(), None);
+            assert_eq!(conn.delete_path("b").unwrap(), None);
+            // A path cannot take a reference on an object that was deleted meanwhile.
+            assert_matches!(conn.upsert_path(&shared("c"), &BTreeSet::new(), true), Err(Error::ObjectGone));
+            assert!(conn.load_path("c").unwrap().is_none());
+        }};
+    }
+
+    macro_rules! check_chunks {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let chunk = |hash: &str, size: i64| (hash.to_string(), size);
+            let written = |hashes: &[&str]| hashes.iter().map(|hash| hash.to_string()).collect::<HashSet<_>>();
+            // Chunks are only recorded once they were written.
+            let batch = [chunk("x", 10), chunk("y", 20)];
+            assert_eq!(conn.claim_chunks("nar/a.nar", 0, &batch, &written(&["x"])).unwrap(), vec!["y"]);
+            assert!(!conn.is_chunked("nar/a.nar").unwrap());
+            assert!(conn.stored_chunks(&["x".to_string()]).unwrap().is_empty());
+            assert!(conn.claim_chunks("nar/a.nar", 0, &batch, &written(&["x", "y"])).unwrap().is_empty());
+            assert!(conn.claim_chunks("nar/a.nar", 2, &[chunk("x", 10)], &written(&[])).unwrap().is_empty());
+            let batch = [chunk("y", 20), chunk("z", 30)];
+            assert!(conn.claim_chunks("nar/b.nar", 0, &batch, &written(&["z"])).unwrap().is_empty());
+            assert_eq!(conn.stored_chunks(&["z".to_string(), "w".to_string()]).unwrap(), written(&["z"]));
+            assert!(conn.is_chunked("nar/a.nar").unwrap());
+            assert!(!conn.is_chunked("nar/c.nar").unwrap());
+            assert_eq!(conn.manifest("nar/a.nar").unwrap(), vec!["x", "y", "x"]);
+            assert_eq!(conn.manifests().unwrap()["nar/a.nar"], vec![chunk("x", 10), chunk("y", 20), chunk("x", 10)]);
+
+            let stats = conn.chunk_stats().unwrap();
+            assert_eq!((stats.chunks, stats.stored_bytes, stats.nar_bytes), (3, 60, 90));
+
+            let mut unused = conn.release_manifest("nar/b.nar").unwrap();
+            unused.sort();
+            assert_eq!(unused, vec!["z"]);
+            assert!(conn.chunk_hashes().unwrap().contains(&"y".to_string()));
+            assert_eq!(conn.release_manifest("nar/a.nar").unwrap().len(), 2);
+            assert!(conn.chunk_hashes().unwrap().is_empty());
+        }};
+    }
+
+    fn embedded_versions<C: EmbeddedMigrations>(_conn: &C) -> &'static [&'static str] {
+        C::embedded_versions()
+    }
+
+    macro_rules! check_schema_too_new {
+        ($conn:expr) => {{
+            let conn = $conn;
+            crate::migrations::run(&conn).unwrap();
+            let applied = conn.previously_run_migration_versions().unwrap();
+            assert!(embedded_versions(&conn).iter().all(|version| applied.contains(*version)));
+            crate::migrations::run(&conn).unwrap();
+
+            diesel::sql_query("INSERT INTO __diesel_schema_migrations (version) VALUES ('99991231235959')")
+                .execute(&conn)
+                .unwrap();
+            assert_matches!(crate::migrations::run(&conn), Err(Error::SchemaTooNew(version)) if version == "99991231235959");
+        }};
+    }
+
+    #[test]
+    fn sqlite_paths_roundtrip() {
+        check_paths_roundtrip!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_closure() {
+        check_closure!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_objects() {
+        check_objects!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_chunks() {
+        check_chunks!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_pins() {
+        check_pins!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_schema_too_new() {
+        check_schema_too_new!(SqliteConnection::establish(":memory:").unwrap());
+    }
+
+    /// Connects to the database given in `NYANCACHE_TEST_POSTGRES_URL`, inside a
+    /// transaction that is rolled back afterwards.
+    ///
+    /// The tests using it are ignored by default and run with `cargo test -- --ignored`.
+    fn postgres() -> PgConnection {
+        let url = std::env::var("NYANCACHE_TEST_POSTGRES_URL").expect("NYANCACHE_TEST_POSTGRES_URL is not set");
+        let conn = PgConnection::establish(&url).unwrap();
+        conn.begin_test_transaction().unwrap();
+        conn
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_paths_roundtrip() {
+        check_paths_roundtrip!(postgres());
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_closure() {
+        check_closure!(postgres());
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_pins() {
+        check_pins!(postgres());
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_objects() {
+        check_objects!(postgres());
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_chunks() {
+        check_chunks!(postgres());
+    }
+
+    #[test]
+    #[ignore = "needs NYANCACHE_TEST_POSTGRES_URL"]
+    fn postgres_schema_too_new() {
+        check_schema_too_new!(postgres());
+    }
+}
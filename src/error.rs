@@ -2,7 +2,6 @@ use strum_macros::AsRefStr;
 use thiserror::Error as ThisError;
 
 use rocket::http::{ContentType, Status};
-use rocket::response::content::Json;
 use rocket::response::{Responder, Response};
 use rocket::Request;
 use std::io::Cursor;
@@ -15,33 +14,89 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("{0}")]
     Database(#[from] diesel::result::Error),
+    #[error("{0}")]
+    Migration(#[from] diesel_migrations::RunMigrationsError),
+    #[error("Database schema version {0} is newer than this build")]
+    SchemaTooNew(String),
     #[error("Upload error")]
     Upload,
     #[error("Download error")]
     Download,
+    #[error("Backend error")]
+    Backend,
+    #[error("Invalid configuration: {0}")]
+    Config(&'static str),
     #[error("Unexpected end of input")]
     UnexpectedEof,
     #[error(transparent)]
     Base64(#[from] base64::DecodeError),
     #[error("Bad base32")]
     BadBase32,
+    #[error("Bad hash")]
+    BadHash,
     #[error("Unknown hash type")]
     UnknownHashType,
     #[error("No valid signature")]
     NoValidSignature,
     #[error("Bad narinfo")]
     BadNarInfo,
+    #[error("Bad NAR: {0}")]
+    BadNar(&'static str),
+    #[error("Bad export: {0}")]
+    BadExport(&'static str),
+    #[error("Upload does not match its narinfo: {0}")]
+    InvalidUpload(String),
+    #[error("Size out of range")]
+    SizeOutOfRange,
+    #[error("Too many paths in one request, at most {0} are allowed")]
+    TooManyPaths(usize),
+    #[error("Bad JSON: {0}")]
+    BadJson(serde_json::Error),
     #[error("Not found")]
     NotFound,
+    #[error("Not enough free disk space")]
+    DiskFull,
+    #[error("Still referenced by other paths")]
+    Referenced,
+    #[error("The stored NAR was deleted during the upload, upload it again")]
+    ObjectGone,
+    #[error("{0} paths of the closure are not cached")]
+    IncompleteClosure(usize),
+    #[error("The backend is read-only")]
+    ReadOnly,
+    /// Imports are not atomic: the paths before the failing one stay imported.
+    #[error("{source} (after importing {} paths)", imported.len())]
+    PartialImport { imported: Vec<String>, source: Box<Error> },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-impl<'r> Responder<'r, 'r> for Error {
-    fn respond_to(self, _: &Request) -> rocket::response::Result<'r> {
-        let status = match self {
+impl Error {
+    fn status(&self) -> Status {
+        match self {
             Error::NotFound => Status::NotFound,
+            Error::BadNarInfo
+            | Error::BadNar(_)
+            | Error::BadExport(_)
+            | Error::InvalidUpload(_)
+            | Error::SizeOutOfRange
+            | Error::TooManyPaths(_)
+            | Error::BadJson(_) => Status::BadRequest,
+            Error::DiskFull => Status::InsufficientStorage,
+            Error::Referenced | Error::IncompleteClosure(_) | Error::ObjectGone => Status::Conflict,
+            Error::ReadOnly => Status::Forbidden,
+            Error::PartialImport { source, .. } => source.status(),
             _ => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'r> for Error {
+    fn respond_to(self, _: &Request) -> rocket::response::Result<'r> {
+        let status = self.status();
+        let meta = match &self {
+            Error::PartialImport { imported, .. } => serde_json::json!({ "imported": imported }),
+            _ => serde_json::json!({}),
         };
 
         let body_json = serde_json::json!({
@@ -51,6 +106,7 @@ impl<'r> Responder<'r, 'r> for Error {
                     "title": status.reason(),
                     "detail": format!("{}", self),
                     "code": self.as_ref(),
+                    "meta": meta,
                 }
             ]
         });
@@ -0,0 +1,144 @@
+//! Streams the closure of a path in the format of `nix-store --export`, for carrying it
+//! to machines without access to the cache.
+
+use crate::backend::Backend;
+use crate::chunks::{reassemble, ChunkQueries};
+use crate::db::{db_run, DbConn};
+use crate::error::{Error, Result};
+use crate::models::{DbPath, PathQueries};
+use crate::nixutils::export::{write_next, write_trailer, ExportTrailer};
+use log::warn;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// How much of the export is buffered ahead of the client.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// A path of the closure, with what is needed to read its NAR.
+struct ExportPath {
+    db_path: DbPath,
+    references: Vec<String>,
+    /// The chunks of its object, if it is chunked.
+    manifest: Vec<String>,
+}
+
+/// The export of the closure of a path, which is produced while it is read. Fails unless
+/// the whole closure is cached, since `nix-store --import` would reject it.
+pub async fn export_closure(
+    conn: &DbConn,
+    backend: Arc<dyn Backend + Send + Sync>,
+    id: &str,
+) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+    let key = id.to_string();
+    let (paths, missing) = db_run!(conn, |c| {
+        let mut paths = Vec::new();
+        for db_path in c.closure(&key)? {
+            let references = c.load_path(&db_path.id)?.map(|(_, references)| references).unwrap_or_default();
+            let manifest = match &db_path.url {
+                Some(url) => c.manifest(url)?,
+                None => Vec::new(),
+            };
+            paths.push(ExportPath { db_path, references, manifest });
+        }
+        Ok::<_, diesel::result::Error>((paths, c.missing_references(&key)?.len()))
+    })?;
+    if paths.is_empty() {
+        return Err(Error::NotFound);
+    }
+    if missing > 0 {
+        return Err(Error::IncompleteClosure(missing));
+    }
+
+    let id = id.to_string();
+    let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        // The export ends early when this fails, which `nix-store --import` rejects.
+        if let Err(e) = write_export(backend, sort_topologically(paths), writer).await {
+            warn!("failed to export closure of {}: {}", id, e);
+        }
+    });
+    Ok(Box::new(reader))
+}
+
+async fn write_export<W: AsyncWrite + Unpin>(
+    backend: Arc<dyn Backend + Send + Sync>,
+    paths: Vec<ExportPath>,
+    mut writer: W,
+) -> Result<()> {
+    for path in paths {
+        let db_path = &path.db_path;
+        let mut nar = match path.manifest.is_empty() {
+            true => {
+                let key = db_path.backend_url().ok_or(Error::NotFound)?;
+                db_path.compression()?.decoder(backend.read_nar(key).await?.into_reader())
+            }
+            false => reassemble(backend.clone(), path.manifest),
+        };
+        write_next(&mut writer, true).await?;
+        if tokio::io::copy(&mut nar, &mut writer).await? != db_path.nar_size as u64 {
+            return Err(Error::BadNar("size differs from NarSize"));
+        }
+        let trailer = ExportTrailer {
+            path: db_path.path.clone(),
+            references: path.references.into_iter().collect(),
+            deriver: db_path.deriver.as_ref().map(|deriver| format!("/nix/store/{}", deriver)),
+        };
+        write_trailer(&mut writer, &trailer).await?;
+    }
+    write_next(&mut writer, false).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Orders paths so that each comes after the paths it references, as importing needs.
+fn sort_topologically(paths: Vec<ExportPath>) -> Vec<ExportPath> {
+    let mut unsorted = paths.into_iter().map(|path| (path.db_path.path.clone(), path)).collect::<BTreeMap<_, _>>();
+    let roots = unsorted.keys().cloned().collect::<Vec<_>>();
+    let mut sorted = Vec::new();
+    let mut visited = HashSet::new();
+    for root in roots {
+        // Paths are pushed twice: to visit their references, then to be emitted after them.
+        let mut stack = vec![(root, false)];
+        while let Some((store_path, emit)) = stack.pop() {
+            if emit {
+                sorted.extend(unsorted.remove(&store_path));
+                continue;
+            }
+            let path = match unsorted.get(&store_path) {
+                Some(path) if visited.insert(store_path.clone()) => path,
+                _ => continue,
+            };
+            let references = path.references.iter().filter(|reference| **reference != store_path).cloned().collect::<Vec<_>>();
+            stack.push((store_path, true));
+            stack.extend(references.into_iter().rev().map(|reference| (reference, false)));
+        }
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str, references: &[&str]) -> ExportPath {
+        ExportPath {
+            db_path: DbPath { path: name.to_string(), ..Default::default() },
+            references: references.iter().map(ToString::to_string).collect(),
+            manifest: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn topological_order() {
+        let paths = vec![
+            path("a", &["a", "b", "c"]),
+            path("b", &["d"]),
+            path("c", &["b", "outside"]),
+            path("d", &[]),
+            path("e", &["c"]),
+        ];
+        let sorted = sort_topologically(paths).into_iter().map(|path| path.db_path.path).collect::<Vec<_>>();
+        assert_eq!(sorted, vec!["d", "b", "c", "a", "e"]);
+    }
+}
@@ -0,0 +1,470 @@
+pub mod policy;
+
+use crate::backend::Backend;
+use crate::chunks::{self, ChunkQueries};
+use crate::config::GcConfig;
+use crate::db::{db_run, impl_for_connections, DbConn, Dialect};
+use crate::error::Result;
+use crate::listing;
+use crate::models::{now, DbGcRun, DbPath, DbPin, NewGcRun, PathQueries, CLOSURE_CTE};
+use crate::schema::gc_runs::{self, dsl::gc_runs as all_gc_runs};
+use crate::schema::paths::dsl::paths as all_paths;
+use crate::schema::pins::{self, dsl::pins as all_pins};
+
+use diesel::sql_types::{BigInt, Text};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl};
+use diesel_derives::QueryableByName;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+#[derive(Debug, clap::Args)]
+pub struct GcOptions {
+    /// Only report what would be evicted
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct GcStats {
+    /// Bytes stored before the collection.
+    pub total_bytes: i64,
+    /// Bytes protected by pins, which are never evicted.
+    pub pinned_bytes: i64,
+    pub evicted_paths: i64,
+    pub evicted_bytes: i64,
+    /// The GDSF inflation value after the collection, which is raised to the priority of
+    /// every path evicted for space.
+    pub inflation: f64,
+}
+
+#[derive(Debug, QueryableByName)]
+struct IdRow {
+    #[sql_type = "Text"]
+    id: String,
+}
+
+/// Queries on `pins` and `gc_runs`.
+pub trait GcQueries {
+    /// Ids of all paths in the closure of a pin that has not expired at `now`.
+    fn pinned_ids(&self, now: i64) -> QueryResult<HashSet<String>>;
+    /// Pins a path, replacing an existing pin on it.
+    fn pin(&self, pin: &DbPin) -> QueryResult<()>;
+    fn unpin(&self, id: &str) -> QueryResult<usize>;
+    fn pins(&self) -> QueryResult<Vec<DbPin>>;
+    fn find_pin(&self, id: &str) -> QueryResult<Option<DbPin>>;
+    fn record_gc_run(&self, run: &NewGcRun) -> QueryResult<()>;
+    /// The most recent collections, newest first.
+    fn gc_runs(&self, limit: i64) -> QueryResult<Vec<DbGcRun>>;
+    /// The GDSF inflation value left by the last collection.
+    fn inflation(&self) -> QueryResult<f64>;
+}
+
+impl_for_connections!(GcQueries {
+    fn pinned_ids(&self, now: i64) -> QueryResult<HashSet<String>> {
+        let seed = format!(
+            "SELECT id FROM pins WHERE expires IS NULL OR expires > {}",
+            Self::bind_param(1),
+        );
+        let query = format!("{} SELECT id FROM closure", CLOSURE_CTE.replace("{seed}", &seed));
+        let rows = diesel::sql_query(query).bind::<BigInt, _>(now).load::<IdRow>(self)?;
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    fn pin(&self, pin: &DbPin) -> QueryResult<()> {
+        self.transaction(|| {
+            diesel::delete(all_pins.find(&pin.id)).execute(self)?;
+            diesel::insert_into(all_pins).values(pin).execute(self)?;
+            Ok(())
+        })
+    }
+
+    fn unpin(&self, id: &str) -> QueryResult<usize> {
+        diesel::delete(all_pins.find(id)).execute(self)
+    }
+
+    fn pins(&self) -> QueryResult<Vec<DbPin>> {
+        all_pins.order(pins::id).load(self)
+    }
+
+    fn find_pin(&self, id: &str) -> QueryResult<Option<DbPin>> {
+        all_pins.find(id).first(self).optional()
+    }
+
+    fn record_gc_run(&self, run: &NewGcRun) -> QueryResult<()> {
+        diesel::insert_into(all_gc_runs).values(run).execute(self)?;
+        Ok(())
+    }
+
+    fn gc_runs(&self, limit: i64) -> QueryResult<Vec<DbGcRun>> {
+        all_gc_runs.order(gc_runs::id.desc()).limit(limit).load(self)
+    }
+
+    fn inflation(&self) -> QueryResult<f64> {
+        let inflation = all_gc_runs
+            .select(gc_runs::inflation)
+            .order(gc_runs::id.desc())
+            .first(self)
+            .optional()?;
+        Ok(inflation.unwrap_or(0.0))
+    }
+});
+
+/// Takes the id of a path from either a full store path or a bare hash.
+pub fn path_id(path: &str) -> &str {
+    let name = path.strip_prefix("/nix/store/").unwrap_or(path);
+    name.split('-').next().unwrap_or(name)
+}
+
+/// Removes a path from the database, and its NAR from the backend unless other paths
+/// share it.
+///
+/// The row goes first, so that a failure leaves an orphaned object rather than a path
+/// whose NAR is missing.
+pub async fn evict(conn: &DbConn, backend: &(dyn Backend + Send + Sync), db_path: &DbPath) -> Result<()> {
+    let id = db_path.id.clone();
+    let unused = db_run!(conn, |c| c.delete_path(&id))?;
+    backend.delete_nar(&listing::listing_key(&db_path.id)).await?;
+    if let Some(url) = unused {
+        chunks::delete_object(conn, backend, &url).await?;
+    }
+    Ok(())
+}
+
+/// Whether a path has outlived `max_idle` or `max_age` at `now`. Paths without the
+/// relevant timestamp never expire.
+pub fn is_expired(config: &GcConfig, db_path: &DbPath, now: i64) -> bool {
+    let older_than = |time: Option<i64>, limit: Option<Duration>| match (time, limit) {
+        (Some(time), Some(limit)) => now.saturating_sub(time) > limit.as_secs() as i64,
+        _ => false,
+    };
+    older_than(db_path.last_accessed.or(db_path.registration_time), config.max_idle)
+        || older_than(db_path.registration_time, config.max_age)
+}
+
+/// The object a path is stored in. Paths without a url each have their own.
+fn object_key(db_path: &DbPath) -> &str {
+    db_path.url.as_deref().unwrap_or(&db_path.id)
+}
+
+/// Chooses the paths to evict at `now`: those that are not pinned and have expired, then
+/// more in the order of the configured policy until the cache fits into `max_size` and at
+/// least `to_free` bytes are evicted. `inflation` is the GDSF inflation value left by the
+/// previous collection.
+///
+/// Paths share objects, and chunked objects, whose chunks are listed in `manifests` by
+/// object url, share chunks. Sizes are counted per object or chunk, which only frees
+/// space once the last path using it is evicted.
+pub fn plan<'a>(
+    config: &GcConfig,
+    db_paths: &'a [DbPath],
+    pinned: &HashSet<String>,
+    manifests: &HashMap<String, Vec<(String, i64)>>,
+    now: i64,
+    to_free: i64,
+    inflation: f64,
+) -> (GcStats, Vec<&'a DbPath>) {
+    // What takes up space, which is either a whole object or a chunk, with its size and
+    // how many objects use it.
+    let mut pieces = HashMap::<&str, (i64, usize)>::new();
+    // How many paths use every object, and its pieces.
+    let mut objects = HashMap::<&str, (usize, Vec<&str>)>::new();
+    for db_path in db_paths {
+        let key = object_key(db_path);
+        let object = objects.entry(key).or_insert_with(|| {
+            let object_pieces = match manifests.get(key) {
+                Some(manifest) => manifest
+                    .iter()
+                    .map(|(hash, size)| (hash.as_str(), *size))
+                    .collect::<HashMap<_, _>>()
+                    .into_iter()
+                    .collect(),
+                None => vec![(key, 0)],
+            };
+            for (piece, size) in &object_pieces {
+                let piece = pieces.entry(piece).or_default();
+                piece.0 = *size;
+                piece.1 += 1;
+            }
+            (0, object_pieces.into_iter().map(|(piece, _)| piece).collect())
+        });
+        object.0 += 1;
+        if !manifests.contains_key(key) {
+            let piece = pieces.get_mut(key).expect("added above");
+            piece.0 = piece.0.max(db_path.stored_size());
+        }
+    }
+    let pinned_objects = db_paths
+        .iter()
+        .filter(|db_path| pinned.contains(&db_path.id))
+        .map(object_key)
+        .collect::<HashSet<_>>();
+    let pinned_pieces = pinned_objects
+        .iter()
+        .flat_map(|key| objects[key].1.iter().copied())
+        .collect::<HashSet<_>>();
+
+    let mut stats = GcStats {
+        total_bytes: pieces.values().map(|(size, _)| size).sum(),
+        pinned_bytes: pinned_pieces.iter().map(|piece| pieces[piece].0).sum(),
+        inflation,
+        ..Default::default()
+    };
+    // Evicts a path from the plan, and returns the bytes that this frees.
+    let mut release = |db_path: &DbPath| {
+        let object = objects.get_mut(object_key(db_path)).expect("counted above");
+        object.0 -= 1;
+        if object.0 > 0 {
+            return 0;
+        }
+        let mut freed = 0;
+        for piece in &object.1 {
+            let piece = pieces.get_mut(piece).expect("counted above");
+            piece.1 -= 1;
+            if piece.1 == 0 {
+                freed += piece.0;
+            }
+        }
+        freed
+    };
+
+    let mut victims = Vec::new();
+    let mut candidates = Vec::new();
+    for db_path in db_paths {
+        if pinned.contains(&db_path.id) {
+            continue;
+        } else if is_expired(config, db_path, now) {
+            stats.evicted_bytes += release(db_path);
+            victims.push(db_path);
+        } else if !pinned_objects.contains(object_key(db_path)) {
+            // Evicting paths whose object is pinned frees nothing.
+            candidates.push(db_path);
+        }
+    }
+
+    // Other data on the disk may take up more than the cache could ever free, and then
+    // evicting all of it would be in vain.
+    let evictable = stats.total_bytes - stats.pinned_bytes;
+    let to_free = match to_free > evictable {
+        true => {
+            warn!(
+                "evicting every unpinned path frees {} bytes, which is not enough to reach the high watermark",
+                evictable,
+            );
+            0
+        }
+        false => to_free,
+    };
+    let budget = config
+        .max_size
+        .map_or(i64::MAX, |max_size| max_size.as_u64() as i64)
+        .min(stats.total_bytes - to_free);
+    if stats.total_bytes - stats.evicted_bytes > budget {
+        let policy = config.policy.build();
+        policy::eviction_order(&*policy, &mut candidates);
+        for db_path in candidates {
+            if stats.total_bytes - stats.evicted_bytes <= budget {
+                break;
+            }
+            stats.evicted_bytes += release(db_path);
+            stats.inflation = stats.inflation.max(policy.priority(db_path));
+            victims.push(db_path);
+        }
+    }
+
+    stats.evicted_paths = victims.len() as i64;
+    (stats, victims)
+}
+
+/// Evicts the paths chosen by [`plan`] and records the collection in `gc_runs`.
+pub async fn collect(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    config: &GcConfig,
+    dry_run: bool,
+) -> Result<GcStats> {
+    let started = now();
+    let (db_paths, pinned, manifests, inflation) = db_run!(conn, |c| {
+        all_paths
+            .load::<DbPath>(c)
+            .and_then(|db_paths| Ok((db_paths, c.pinned_ids(started)?, c.manifests()?, c.inflation()?)))
+    })?;
+
+    let to_free = backend.space_to_free().await? as i64;
+    if to_free > 0 {
+        info!("low on disk space, evicting at least {} bytes", to_free);
+    }
+    let (stats, victims) = plan(config, &db_paths, &pinned, &manifests, started, to_free, inflation);
+    for db_path in victims {
+        info!("evicting {} ({} bytes)", db_path.path, db_path.stored_size());
+        if !dry_run {
+            evict(conn, backend, db_path).await?;
+        }
+    }
+
+    if !dry_run {
+        let run = NewGcRun {
+            started,
+            finished: now(),
+            total_bytes: stats.total_bytes,
+            pinned_bytes: stats.pinned_bytes,
+            evicted_paths: stats.evicted_paths,
+            evicted_bytes: stats.evicted_bytes,
+            inflation: stats.inflation,
+        };
+        db_run!(conn, |c| c.record_gc_run(&run))?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use policy::PolicyKind;
+
+    #[test]
+    fn path_ids() {
+        assert_eq!(path_id("/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-cuda"), "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0");
+        assert_eq!(path_id("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0"), "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0");
+    }
+
+    #[test]
+    fn expiry() {
+        let config = GcConfig {
+            max_idle: Some(Duration::from_secs(30)),
+            max_age: Some(Duration::from_secs(90)),
+            ..Default::default()
+        };
+        let path = |registration_time, last_accessed| DbPath {
+            registration_time,
+            last_accessed,
+            ..Default::default()
+        };
+        assert!(!is_expired(&config, &path(None, None), 1000));
+        assert!(!is_expired(&config, &path(Some(950), Some(990)), 1000));
+        assert!(is_expired(&config, &path(Some(950), None), 1000));
+        assert!(is_expired(&config, &path(Some(900), Some(999)), 1000));
+        assert!(is_expired(&config, &path(None, Some(900)), 1000));
+    }
+
+    #[test]
+    fn plan_frees_space() {
+        let path = |id: &str, last_accessed| DbPath {
+            id: id.to_string(),
+            last_accessed: Some(last_accessed),
+            nar_size: 100,
+            ..Default::default()
+        };
+        let db_paths = vec![path("a", 3), path("b", 1), path("c", 2)];
+        let pinned = ["b".to_string()].into_iter().collect();
+        let ids = |victims: Vec<&DbPath>| victims.into_iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+
+        let (stats, victims) = plan(&GcConfig::default(), &db_paths, &pinned, &HashMap::new(), 10, 0, 0.0);
+        assert!(victims.is_empty());
+        assert_eq!(stats.pinned_bytes, 100);
+
+        let (_, victims) = plan(&GcConfig::default(), &db_paths, &pinned, &HashMap::new(), 10, 150, 0.0);
+        assert_eq!(ids(victims), vec!["c", "a"]);
+
+        // Not even evicting everything would free enough.
+        let (_, victims) = plan(&GcConfig::default(), &db_paths, &pinned, &HashMap::new(), 10, 250, 0.0);
+        assert!(victims.is_empty());
+    }
+
+    #[test]
+    fn plan_counts_shared_objects_once() {
+        let path = |id: &str, url: &str, last_accessed| DbPath {
+            id: id.to_string(),
+            url: Some(url.to_string()),
+            last_accessed: Some(last_accessed),
+            nar_size: 100,
+            ..Default::default()
+        };
+        let db_paths = vec![path("a", "nar/x", 1), path("b", "nar/y", 2), path("c", "nar/x", 3), path("d", "nar/z", 4)];
+        let ids = |victims: Vec<&DbPath>| victims.into_iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+
+        let (stats, victims) = plan(&GcConfig::default(), &db_paths, &HashSet::new(), &HashMap::new(), 10, 100, 0.0);
+        assert_eq!(stats.total_bytes, 300);
+        // Evicting "a" frees nothing while "c" still uses its object.
+        assert_eq!(ids(victims), vec!["a", "b"]);
+        assert_eq!(stats.evicted_bytes, 100);
+
+        let (stats, victims) = plan(&GcConfig::default(), &db_paths, &HashSet::new(), &HashMap::new(), 10, 200, 0.0);
+        assert_eq!(ids(victims), vec!["a", "b", "c"]);
+        assert_eq!(stats.evicted_bytes, 200);
+
+        // Paths sharing a pinned object are not evicted for space.
+        let pinned = ["c".to_string()].into_iter().collect();
+        let (stats, victims) = plan(&GcConfig::default(), &db_paths, &pinned, &HashMap::new(), 10, 150, 0.0);
+        assert_eq!(stats.pinned_bytes, 100);
+        assert_eq!(ids(victims), vec!["b", "d"]);
+        assert_eq!(stats.evicted_bytes, 200);
+    }
+
+    #[test]
+    fn plan_counts_chunks() {
+        let path = |id: &str, url: &str, last_accessed, file_size| DbPath {
+            id: id.to_string(),
+            url: Some(url.to_string()),
+            last_accessed: Some(last_accessed),
+            file_size: Some(file_size),
+            ..Default::default()
+        };
+        // The compressed sizes of chunked objects are not what they take up.
+        let db_paths = vec![path("a", "nar/x", 1, 1000), path("b", "nar/y", 2, 1000), path("c", "nar/z", 3, 70)];
+        let chunk = |hash: &str, size| (hash.to_string(), size);
+        let manifests = HashMap::from([
+            ("nar/x".to_string(), vec![chunk("c1", 100), chunk("c2", 50), chunk("c1", 100)]),
+            ("nar/y".to_string(), vec![chunk("c2", 50), chunk("c3", 30)]),
+        ]);
+        let ids = |victims: Vec<&DbPath>| victims.into_iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        let config = GcConfig::default();
+
+        let (stats, victims) = plan(&config, &db_paths, &HashSet::new(), &manifests, 10, 100, 0.0);
+        assert_eq!(stats.total_bytes, 250);
+        // "c2" stays for "b".
+        assert_eq!((ids(victims), stats.evicted_bytes), (vec!["a".to_string()], 100));
+
+        let (stats, victims) = plan(&config, &db_paths, &HashSet::new(), &manifests, 10, 160, 0.0);
+        assert_eq!((ids(victims), stats.evicted_bytes), (vec!["a".to_string(), "b".to_string()], 180));
+
+        let pinned = ["b".to_string()].into_iter().collect();
+        let (stats, victims) = plan(&config, &db_paths, &pinned, &manifests, 10, 150, 0.0);
+        assert_eq!(stats.pinned_bytes, 80);
+        assert_eq!((ids(victims), stats.evicted_bytes), (vec!["a".to_string(), "c".to_string()], 170));
+    }
+
+    /// Adds paths downloaded twice each to a cache with room for two, until `old`, which
+    /// was downloaded ten times before, is evicted. Returns how many paths that took.
+    fn rounds_until_evicted(policy: PolicyKind) -> Option<usize> {
+        let path = |id: &str, hit_count, inflation| DbPath {
+            id: id.to_string(),
+            hit_count,
+            nar_size: 100,
+            inflation,
+            ..Default::default()
+        };
+        let config = GcConfig { max_size: Some(200.into()), policy, ..Default::default() };
+        let mut db_paths = vec![path("old", 10, 0.0)];
+        let mut inflation = 0.0;
+        for round in 0..100 {
+            db_paths.push(path(&format!("new-{}", round), 2, inflation));
+            let (stats, victims) = plan(&config, &db_paths, &HashSet::new(), &HashMap::new(), 10, 0, inflation);
+            let victims = victims.into_iter().map(|p| p.id.clone()).collect::<HashSet<_>>();
+            if victims.contains("old") {
+                return Some(round);
+            }
+            db_paths.retain(|p| !victims.contains(&p.id));
+            inflation = stats.inflation;
+        }
+        None
+    }
+
+    #[test]
+    fn gdsf_ages_stale_paths() {
+        assert_eq!(rounds_until_evicted(PolicyKind::Lfu), None);
+        let rounds = rounds_until_evicted(PolicyKind::Gdsf).unwrap();
+        assert!(rounds > 1, "evicted after {} rounds", rounds);
+    }
+}
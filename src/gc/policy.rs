@@ -0,0 +1,132 @@
+use crate::models::DbPath;
+
+use serde::Deserialize;
+use std::borrow::Borrow;
+
+/// Decides which unpinned paths are evicted first when the cache exceeds its size budget.
+pub trait EvictionPolicy {
+    /// How valuable it is to keep a path. The lowest values are evicted first.
+    fn priority(&self, db_path: &DbPath) -> f64;
+}
+
+/// Evicts the least recently used paths first.
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn priority(&self, db_path: &DbPath) -> f64 {
+        last_used(db_path).unwrap_or(0) as f64
+    }
+}
+
+/// Evicts the least frequently downloaded paths first.
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn priority(&self, db_path: &DbPath) -> f64 {
+        db_path.hit_count as f64
+    }
+}
+
+/// Greedy-Dual-Size-Frequency: evicts the paths with the fewest downloads per stored byte
+/// first, which keeps as many requests as possible served from a given budget.
+///
+/// Every path is evicted for space at a priority of at least the inflation value, which
+/// is raised to it. Paths used since start out from the raised value, so that a path
+/// downloaded often long ago eventually gives way to what is popular today.
+pub struct Gdsf;
+
+impl EvictionPolicy for Gdsf {
+    fn priority(&self, db_path: &DbPath) -> f64 {
+        // The upload counts as a use, so that new paths are not evicted before their first
+        // download.
+        let frequency = (db_path.hit_count + 1) as f64;
+        db_path.inflation + frequency / db_path.stored_size().max(1) as f64
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyKind {
+    #[default]
+    Lru,
+    Lfu,
+    Gdsf,
+}
+
+impl PolicyKind {
+    /// The name of the policy in the configuration and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PolicyKind::Lru => "lru",
+            PolicyKind::Lfu => "lfu",
+            PolicyKind::Gdsf => "gdsf",
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn EvictionPolicy + Send + Sync> {
+        match self {
+            PolicyKind::Lru => Box::new(Lru),
+            PolicyKind::Lfu => Box::new(Lfu),
+            PolicyKind::Gdsf => Box::new(Gdsf),
+        }
+    }
+}
+
+/// When a path was last used, counting its upload as a use.
+fn last_used(db_path: &DbPath) -> Option<i64> {
+    db_path.last_accessed.or(db_path.registration_time)
+}
+
+/// Sorts paths into the order in which `policy` evicts them. Ties go to the least
+/// recently used path.
+pub fn eviction_order<P: Borrow<DbPath>>(policy: &dyn EvictionPolicy, db_paths: &mut [P]) {
+    db_paths.sort_by(|a, b| {
+        let (a, b) = (a.borrow(), b.borrow());
+        policy
+            .priority(a)
+            .total_cmp(&policy.priority(b))
+            .then_with(|| last_used(a).cmp(&last_used(b)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(id: &str, last_accessed: i64, hit_count: i64, nar_size: i64) -> DbPath {
+        DbPath {
+            id: id.to_string(),
+            last_accessed: Some(last_accessed),
+            hit_count,
+            nar_size,
+            ..Default::default()
+        }
+    }
+
+    fn order(policy: &dyn EvictionPolicy) -> Vec<String> {
+        let mut db_paths = vec![
+            // a large toolchain that is downloaded all the time
+            path("toolchain", 100, 50, 1_000_000),
+            // a small path used once, recently
+            path("small", 300, 1, 1_000),
+            // a medium path that nobody downloaded in a while
+            path("stale", 50, 2, 10_000),
+        ];
+        eviction_order(policy, &mut db_paths);
+        db_paths.into_iter().map(|db_path| db_path.id).collect()
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(order(&Lru), vec!["stale", "toolchain", "small"]);
+        assert_eq!(order(&Lfu), vec!["small", "stale", "toolchain"]);
+        assert_eq!(order(&Gdsf), vec!["toolchain", "stale", "small"]);
+    }
+
+    #[test]
+    fn ties_go_to_least_recently_used() {
+        let mut db_paths = vec![path("new", 200, 3, 100), path("old", 100, 3, 100)];
+        eviction_order(&Lfu, &mut db_paths);
+        assert_eq!(db_paths[0].id, "old");
+    }
+}
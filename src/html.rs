@@ -0,0 +1,82 @@
+//! Helpers for the server-rendered HTML pages.
+
+use rocket::response::content::Html;
+use std::time::{Duration, UNIX_EPOCH};
+
+const STYLE: &str = "
+    body { font-family: sans-serif; margin: 2em auto; max-width: 60em; padding: 0 1em; }
+    table { border-collapse: collapse; width: 100%; }
+    th, td { text-align: left; padding: 0.2em 0.6em; border-bottom: 1px solid #ddd; }
+    td.number, th.number { text-align: right; }
+    code { word-break: break-all; }
+";
+
+/// Escapes text for use in HTML content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats a byte count with a binary unit, such as `1.5 MiB`.
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Formats seconds since the epoch, or a dash if there are none.
+pub fn format_time(time: Option<i64>) -> String {
+    match time {
+        Some(time) if time >= 0 => {
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time as u64)).to_string()
+        }
+        _ => "-".to_string(),
+    }
+}
+
+/// Wraps already escaped `body` into a complete page.
+pub fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{} - nyancache</title><style>{}</style></head>\n<body>\n<nav><a href=\"/\">nyancache</a></nav>\n{}\n</body></html>\n",
+        escape(title),
+        STYLE,
+        body
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_size(1000), "1000 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+        assert_eq!(format_time(Some(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(None), "-");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("<a href=\"x\">&'</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;");
+    }
+}
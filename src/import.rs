@@ -0,0 +1,127 @@
+//! Imports the output of `nix-store --export`, for tooling that cannot upload to a binary
+//! cache.
+//!
+//! Every NAR in the export is compressed and stored like an upload of it with its narinfo,
+//! so it is checked, deduplicated, chunked and listed the same way.
+
+use crate::db::DbConn;
+use crate::error::{Error, Result};
+use crate::gc::path_id;
+use crate::models::{now, DbPath};
+use crate::nixutils::export::{read_next, read_trailer};
+use crate::nixutils::nar::{NarNode, NarReader, NarWriter};
+use crate::nixutils::{HashType, HashingReader};
+use crate::scrub::Measured;
+use crate::{complete_upload, State};
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How much of a NAR is buffered ahead of its compression.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Paths imported by this process, to tell their NARs apart.
+static IMPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// Imports every path of an export, and returns their store paths.
+///
+/// This is not atomic. When a path fails, the paths before it stay imported and are
+/// listed in the returned [`Error::PartialImport`].
+pub async fn import<R: AsyncRead + Unpin>(conn: &DbConn, state: &State, mut reader: R) -> Result<Vec<String>> {
+    let mut imported = Vec::new();
+    loop {
+        let next = match read_next(&mut reader).await {
+            Ok(true) => import_path(conn, state, &mut reader).await.map(Some),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let source = match next {
+            Ok(Some(path)) => {
+                imported.push(path);
+                continue;
+            }
+            Ok(None) => return Ok(imported),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Error::BadExport("unexpected end of input"),
+            Err(e) => e,
+        };
+        return match imported.is_empty() {
+            true => Err(source),
+            false => Err(Error::PartialImport { imported, source: Box::new(source) }),
+        };
+    }
+}
+
+async fn import_path<R: AsyncRead + Unpin>(conn: &DbConn, state: &State, reader: &mut R) -> Result<String> {
+    // The store path and the hashes only come after the NAR, so it needs a name of its own.
+    let url = format!(
+        "import-{}-{}-{}{}",
+        std::process::id(),
+        now(),
+        IMPORTED.fetch_add(1, Ordering::Relaxed),
+        state.compression.nar_extension(),
+    );
+    let mut nar_reader = NarReader::new(reader);
+    let (pipe_reader, pipe_writer) = tokio::io::duplex(PIPE_SIZE);
+    let copy = async {
+        // Copying entry by entry finds the end of the NAR, which is not length prefixed.
+        copy_nar(&mut nar_reader, NarWriter::new(pipe_writer)).await
+    };
+    let store = async {
+        let mut nar = HashingReader::new(pipe_reader, HashType::Sha256)?;
+        let mut file = HashingReader::new(state.compression.encoder(&mut nar), HashType::Sha256)?;
+        state.backend.write_nar(&url, &mut file).await?;
+        let file = file.finish();
+        Ok::<_, Error>((nar.finish(), file))
+    };
+    let ((nar_hash, nar_size), (file_hash, file_size)) = match tokio::try_join!(copy, store) {
+        Ok((_, hashes)) => hashes,
+        Err(e) => {
+            state.backend.abort_nar(&url).await?;
+            return Err(e);
+        }
+    };
+    let trailer = match read_trailer(nar_reader.into_inner().await?).await {
+        Ok(trailer) => trailer,
+        Err(e) => {
+            state.backend.abort_nar(&url).await?;
+            return Err(e);
+        }
+    };
+
+    let id = path_id(&trailer.path).to_string();
+    let db_path = DbPath {
+        id,
+        path: trailer.path.clone(),
+        nar_size: i64::try_from(nar_size).map_err(|_| Error::SizeOutOfRange)?,
+        nar_hash: nar_hash.to_string(),
+        file_size: Some(i64::try_from(file_size).map_err(|_| Error::SizeOutOfRange)?),
+        file_hash: Some(file_hash.to_string()),
+        url: Some(format!("nar/{}", url)),
+        compression: Some(state.compression.as_ref().to_string()),
+        deriver: trailer.deriver.map(|deriver| store_name(&deriver)),
+        ..Default::default()
+    };
+    // The narinfo was made from the NAR just stored, so only its structure needed checking.
+    let measured = Measured::new(state.compression.clone(), (file_hash, file_size), (nar_hash, nar_size));
+    complete_upload(conn, state, &url, db_path, trailer.references, Some(measured)).await?;
+    info!("imported {}", trailer.path);
+    Ok(trailer.path)
+}
+
+/// The name of a store path, as in narinfos.
+fn store_name(path: &str) -> String {
+    path.strip_prefix("/nix/store/").unwrap_or(path).to_string()
+}
+
+/// Copies a NAR while parsing it, which leaves the reader right after the end of the NAR.
+async fn copy_nar<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut NarReader<R>, mut writer: NarWriter<W>) -> Result<()> {
+    while let Some(entry) = reader.next().await? {
+        match entry.node {
+            NarNode::Directory => writer.directory(&entry.path).await?,
+            NarNode::Symlink { target } => writer.symlink(&entry.path, &target).await?,
+            NarNode::File { executable, size, .. } => writer.file(&entry.path, executable, size, &mut *reader).await?,
+        }
+    }
+    writer.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! The `.ls` listings of NAR contents which Nix fetches to browse store paths without
+//! downloading them.
+
+use crate::backend::Backend;
+use crate::chunks;
+use crate::db::DbConn;
+use crate::error::{Error, Result};
+use crate::models::DbPath;
+use crate::nixutils::nar::{NarNode, NarReader};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListingNode {
+    Regular {
+        size: u64,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+        #[serde(rename = "narOffset")]
+        nar_offset: u64,
+    },
+    Symlink {
+        target: String,
+    },
+    Directory {
+        entries: BTreeMap<String, ListingNode>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Listing {
+    pub version: u32,
+    pub root: ListingNode,
+}
+
+/// The backend key of the listing of a path.
+pub fn listing_key(id: &str) -> String {
+    format!("{}.ls", id)
+}
+
+impl ListingNode {
+    /// The node at a relative path below this one, without following symlinks.
+    pub fn lookup<'a, I: IntoIterator<Item = &'a str>>(&self, path: I) -> Option<&ListingNode> {
+        let mut node = self;
+        for name in path {
+            node = match node {
+                ListingNode::Directory { entries } => entries.get(name)?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+}
+
+/// Walks a NAR and lists its contents.
+pub async fn list_nar<R: AsyncRead + Unpin>(nar: R) -> Result<Listing> {
+    let mut reader = NarReader::new(nar);
+    let mut root = None;
+    while let Some(entry) = reader.next().await? {
+        let node = match entry.node {
+            NarNode::Directory => ListingNode::Directory { entries: BTreeMap::new() },
+            NarNode::Symlink { target } => ListingNode::Symlink { target },
+            NarNode::File { executable, size, nar_offset } => ListingNode::Regular { size, executable, nar_offset },
+        };
+        if entry.path.is_empty() {
+            root = Some(node);
+            continue;
+        }
+        // Entries come in archive order, so their parent directories are listed already.
+        let mut dir = root.as_mut().ok_or(Error::BadNar("entry before the root"))?;
+        let mut components = entry.path.split('/').peekable();
+        while let Some(name) = components.next() {
+            let entries = match dir {
+                ListingNode::Directory { entries } => entries,
+                _ => return Err(Error::BadNar("entry below a non-directory")),
+            };
+            if components.peek().is_none() {
+                entries.insert(name.to_string(), node);
+                break;
+            }
+            dir = entries.get_mut(name).ok_or(Error::BadNar("entry outside of any directory"))?;
+        }
+    }
+    reader.finish().await?;
+    Ok(Listing {
+        version: 1,
+        root: root.ok_or(Error::BadNar("empty archive"))?,
+    })
+}
+
+/// Lists the NAR of a stored path and saves the listing next to it.
+pub async fn store_listing(conn: &DbConn, backend: &(dyn Backend + Send + Sync), db_path: &DbPath) -> Result<Vec<u8>> {
+    let listing = list_nar(chunks::open_nar(conn, backend, db_path).await?).await?;
+    let json = serde_json::to_vec(&listing).map_err(|_| Error::Backend)?;
+    let key = listing_key(&db_path.id);
+    backend.write_nar(&key, &mut json.as_slice()).await?;
+    backend.finish_nar(&key).await?;
+    Ok(json)
+}
+
+/// The JSON listing of a path. Listings are only generated on upload and by the scrubber,
+/// since listing a NAR means reading all of it.
+pub async fn read_listing(backend: &(dyn Backend + Send + Sync), id: &str) -> Result<Vec<u8>> {
+    let mut json = Vec::new();
+    backend.read_nar(&listing_key(id)).await?.into_reader().read_to_end(&mut json).await?;
+    Ok(json)
+}
+
+/// Like [`store_listing`], but only logs failures. The path then has no listing until
+/// `nyancache scrub --listings` fills in the missing ones.
+pub async fn try_store_listing(conn: &DbConn, backend: &(dyn Backend + Send + Sync), db_path: &DbPath) {
+    if let Err(e) = store_listing(conn, backend, db_path).await {
+        warn!("failed to list {}: {}", db_path.path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixutils::nar::NarWriter;
+
+    #[rocket::async_test]
+    async fn listing() {
+        let mut writer = NarWriter::new(Vec::new());
+        writer.directory("").await.unwrap();
+        writer.directory("bin").await.unwrap();
+        writer.file("bin/hello", true, 3, &b"abc"[..]).await.unwrap();
+        writer.symlink("lib", "bin").await.unwrap();
+        writer.file("readme", false, 2, &b"hi"[..]).await.unwrap();
+        let nar = writer.finish().await.unwrap();
+
+        let listing = list_nar(&nar[..]).await.unwrap();
+        let json = serde_json::to_value(&listing).unwrap();
+        let offset = |path: &str| json.pointer(path).unwrap().as_u64().unwrap() as usize;
+        assert_eq!(&nar[offset("/root/entries/bin/entries/hello/narOffset")..][..3], b"abc");
+        assert_eq!(&nar[offset("/root/entries/readme/narOffset")..][..2], b"hi");
+        let mut expected = serde_json::json!({
+            "version": 1,
+            "root": {"type": "directory", "entries": {
+                "bin": {"type": "directory", "entries": {
+                    "hello": {"type": "regular", "size": 3, "executable": true, "narOffset": 0},
+                }},
+                "lib": {"type": "symlink", "target": "bin"},
+                "readme": {"type": "regular", "size": 2, "narOffset": 0},
+            }},
+        });
+        expected["root"]["entries"]["bin"]["entries"]["hello"]["narOffset"] = offset("/root/entries/bin/entries/hello/narOffset").into();
+        expected["root"]["entries"]["readme"]["narOffset"] = offset("/root/entries/readme/narOffset").into();
+        assert_eq!(json, expected);
+    }
+}
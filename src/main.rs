@@ -1,41 +1,72 @@
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
+mod access;
+mod api;
+mod auth;
+mod browse;
 mod error;
+mod export;
 mod models;
 mod nixutils;
 mod schema;
 mod backend;
+mod chunks;
+mod config;
+mod db;
+mod gc;
+mod html;
+mod import;
+mod listing;
+mod migrations;
+mod orphans;
+mod scrub;
+mod simulate;
+mod web;
 
-use std::collections::BTreeMap;
-use std::io::Cursor;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use auth::Admin;
 use error::{Error, Result};
-use models::DbPath;
-use nixutils::NarInfo;
+use models::{fingerprint_mismatch, now, DbPath, DbPin, PathQueries};
+use nixutils::{Compression, NarInfo};
 use schema::paths::dsl::paths;
-use schema::paths::{id as db_id, url as db_url};
-use backend::{Backend, local::LocalBackend, NarResponder};
+use schema::paths::url as db_url;
+use access::Accesses;
+use backend::{Backend, NarResponder};
+use config::{ChunkingConfig, Config, GcConfig};
+use gc::policy::PolicyKind;
+use db::{db_run, DbConn};
+use chunks::ChunkQueries;
+use gc::GcQueries;
+use simulate::{TraceKind, TraceWriter};
 
+use clap::Parser;
 use diesel::RunQueryDsl;
 use diesel::QueryDsl;
 use diesel::ExpressionMethods;
-use log::warn;
+use diesel::OptionalExtension;
+use log::{error, info, warn};
 use rocket::data::ToByteUnit;
-use rocket::http::{ContentType, Status};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::http::ContentType;
 use rocket::request::FromParam;
-use rocket::response::{Responder, Response};
-use rocket::Request;
-use rocket_sync_db_pools::{database, diesel as rocket_diesel};
-use tokio::sync::Mutex;
-use s3::Bucket;
-use s3::creds::Credentials;
-
+use rocket::{Build, Rocket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Mutex, Notify};
 
-#[database("sqlite_nyancache")]
-struct DbConn(rocket_diesel::SqliteConnection);
+/// How much of an upload is buffered ahead of the backend and of its measurement.
+const PIPE_SIZE: usize = 64 * 1024;
 
 #[rocket::get("/nix-cache-info")]
 fn nix_cache_info() -> &'static str {
@@ -63,24 +94,93 @@ macro_rules! generate_fromparam_ext {
 }
 
 generate_fromparam_ext!(NarinfoName, ".narinfo");
-generate_fromparam_ext!(NarXzName, ".nar.xz");
+generate_fromparam_ext!(LsName, ".ls");
+
+/// The file name of a NAR in any compression.
+struct NarName<'a>(&'a str);
+impl<'a> FromParam<'a> for NarName<'a> {
+    type Error = ();
+
+    fn from_param(param: &'a str) -> std::result::Result<Self, Self::Error> {
+        match param.split_once('.') {
+            Some((_, extension)) if extension == "nar" || extension.starts_with("nar.") => Ok(NarName(param)),
+            _ => Err(()),
+        }
+    }
+}
 
 #[rocket::get("/<name>")]
 async fn get_narinfo(
     conn: DbConn,
     name: NarinfoName<'_>,
+    state: &rocket::State<Arc<State>>,
 ) -> Result<String> {
-    let id = name.0.to_string();
-    let matches = conn.run(move |c| {
-        paths.filter(db_id.eq(id)).load::<DbPath>(c)
-    })
-    .await?;
-    let db_path = matches.get(0).cloned().ok_or(Error::NotFound)?;
-    let nar_info: NarInfo = db_path.into();
+    let (mut db_path, references, chunked) = match load_narinfo(&conn, name.0).await? {
+        Some(found) => found,
+        None if register_served(&conn, state, name.0).await? => {
+            load_narinfo(&conn, name.0).await?.ok_or(Error::NotFound)?
+        }
+        None => {
+            // Clients only fetch NARs after their narinfo, so this is where misses show.
+            if let Some(access_log) = &state.access_log {
+                access_log.record_miss(name.0).await;
+            }
+            return Err(Error::NotFound);
+        }
+    };
+    state.accesses.record(&db_path.id, false).await;
+    if let Some(access_log) = &state.access_log {
+        access_log.record(TraceKind::Narinfo, &db_path).await;
+    }
+    if chunked {
+        // The NAR is compressed anew on every download, so the stored file hash and size
+        // no longer apply.
+        db_path.file_hash = None;
+        db_path.file_size = None;
+    }
+    let nar_info = NarInfo::from((db_path, references));
 
     Ok(nar_info.to_string())
 }
 
+/// Loads a path for its narinfo, along with whether its object is chunked.
+async fn load_narinfo(conn: &DbConn, id: &str) -> Result<Option<(DbPath, Vec<String>, bool)>> {
+    let id = id.to_string();
+    Ok(db_run!(conn, |c| {
+        match c.load_path(&id)? {
+            Some((db_path, references)) => {
+                let chunked = match &db_path.url {
+                    Some(url) => c.is_chunked(url)?,
+                    None => false,
+                };
+                Ok(Some((db_path, references, chunked)))
+            }
+            None => Ok::<_, diesel::result::Error>(None),
+        }
+    })?)
+}
+
+/// Adds a path to the database that the backend serves without an upload, so that it is
+/// downloaded and collected like any other. Returns whether there was such a path.
+async fn register_served(conn: &DbConn, state: &rocket::State<Arc<State>>, id: &str) -> Result<bool> {
+    let nar_info = match state.backend.find_path(id).await? {
+        Some(nar_info) => nar_info,
+        None => return Ok(false),
+    };
+    let references = nar_info.references.clone();
+    let mut db_path = DbPath::try_from(nar_info)?;
+    db_path.id = id.to_string();
+    db_path.registration_time = Some(now());
+    db_run!(conn, |c| c.upsert_path(&db_path, &references, false))?;
+    Ok(true)
+}
+
+#[rocket::get("/<name>", rank = 2)]
+async fn get_listing(name: LsName<'_>, state: &rocket::State<Arc<State>>) -> Result<(ContentType, Vec<u8>)> {
+    let json = listing::read_listing(&*state.backend, name.0).await?;
+    Ok((ContentType::JSON, json))
+}
+
 #[rocket::put("/<name>", data = "<input>")]
 async fn put_narinfo(
     conn: DbConn,
@@ -88,75 +188,174 @@ async fn put_narinfo(
     input: &str,
     state: &rocket::State<Arc<State>>,
 ) -> Result<()> {
-    let mut nar_info = DbPath::from(NarInfo::from_str(input)?);
+    let nar_info = NarInfo::from_str(input)?;
+    let references = nar_info.references.clone();
+    let mut nar_info = DbPath::try_from(nar_info)?;
     nar_info.id = name.0.to_string();
     if let Some(url) = nar_info.url.clone().and_then(|full| full.strip_prefix("nar/").map(|x| x.to_string())) {
-        add_incomplete(&conn, state, &url, IncompleteUpload::NarInfo(nar_info)).await?;
+        add_incomplete(&conn, state, &url, IncompleteUpload::NarInfo(Box::new(nar_info), references)).await?;
     } else {
         warn!("narinfo missing url");
     }
     Ok(())
 }
 
+#[rocket::delete("/<name>?<only_unreferenced>")]
+async fn delete_narinfo(
+    conn: DbConn,
+    name: NarinfoName<'_>,
+    only_unreferenced: Option<bool>,
+    state: &rocket::State<Arc<State>>,
+    _admin: Admin,
+) -> Result<()> {
+    let id = name.0.to_string();
+    let (db_path, referrers) = db_run!(conn, |c| {
+        c.load_path(&id).and_then(|path| Ok((path.map(|(db_path, _)| db_path), c.referrers(&id)?)))
+    })?;
+    // Paths commonly reference themselves, which does not keep them alive.
+    if only_unreferenced.unwrap_or(false) && referrers.iter().any(|referrer| referrer.id != name.0) {
+        return Err(Error::Referenced);
+    }
+    let url = db_path.as_ref().and_then(|db_path| db_path.backend_url());
+    let discarded = discard_queued(state, name.0, url).await?;
+    match db_path {
+        Some(db_path) => {
+            gc::evict(&conn, &*state.backend, &db_path).await?;
+            info!("deleted {}", db_path.path);
+            Ok(())
+        }
+        None if discarded => Ok(()),
+        None => Err(Error::NotFound),
+    }
+}
+
+/// Drops the pending uploads of a path, and the NARs uploaded without their narinfo.
+/// Returns whether there were any.
+async fn discard_queued(state: &rocket::State<Arc<State>>, id: &str, url: Option<&str>) -> Result<bool> {
+    let discarded = {
+        let mut queued_uploads = state.queued_uploads.lock().await;
+        let urls = queued_uploads
+            .iter()
+            .filter(|(queued_url, part)| match part {
+                IncompleteUpload::Nar(_) => Some(queued_url.as_str()) == url,
+                IncompleteUpload::NarInfo(nar_info, _) => nar_info.id == id,
+            })
+            .map(|(queued_url, _)| queued_url.clone())
+            .collect::<Vec<_>>();
+        urls.into_iter()
+            .filter_map(|url| queued_uploads.remove(&url).map(|part| (url, part)))
+            .collect::<Vec<_>>()
+    };
+    for (url, part) in &discarded {
+        if let IncompleteUpload::Nar(_) = part {
+            state.backend.abort_nar(url).await?;
+        }
+    }
+    Ok(!discarded.is_empty())
+}
+
 #[rocket::get("/nar/<name>")]
 async fn get_nar(
     conn: DbConn,
-    name: NarXzName<'_>,
+    name: NarName<'_>,
     state: &rocket::State<Arc<State>>,
 ) -> Result<NarResponder> {
-    let id = name.0.to_string();
-    let matches = conn.run(move |c| {
-        paths.filter(db_url.eq(&format!("nar/{}.nar.xz", id))).load::<DbPath>(c)
-    })
-    .await?;
-    let _db_path = matches.get(0).cloned().ok_or(Error::NotFound)?;
+    let url = format!("nar/{}", name.0);
+    let db_path = db_run!(conn, |c| paths.filter(db_url.eq(&url)).first::<DbPath>(c).optional())?
+        .ok_or(Error::NotFound)?;
+    state.accesses.record(&db_path.id, true).await;
+    if let Some(access_log) = &state.access_log {
+        access_log.record(TraceKind::Nar, &db_path).await;
+    }
+
+    let object_url = db_path.url.clone().unwrap_or_default();
+    let manifest = db_run!(conn, |c| c.manifest(&object_url))?;
+    if !manifest.is_empty() {
+        let nar = chunks::reassemble(state.backend.clone(), manifest);
+        return Ok(NarResponder::Reader(db_path.compression()?.encoder(nar)));
+    }
 
-    let url = format!("{}.nar.xz", name.0);
-    Ok(state.backend.read_nar(&url).await?)
+    state.backend.read_nar(name.0).await
 }
 
 #[rocket::head("/nar/<name>")]
 async fn head_nar(
     conn: DbConn,
-    name: NarXzName<'_>,
-    state: &rocket::State<Arc<State>>,
+    name: NarName<'_>,
 ) -> Result<()> {
-    let id = name.0.to_string();
-    let matches = conn.run(move |c| {
-        paths.filter(db_url.eq(&format!("nar/{}.nar.xz", id))).load::<DbPath>(c)
-    })
-    .await?;
-    let _db_path = matches.get(0).cloned().ok_or(Error::NotFound)?;
+    let url = format!("nar/{}", name.0);
+    let matches = db_run!(conn, |c| {
+        paths.filter(db_url.eq(&url)).load::<DbPath>(c)
+    })?;
+    let _db_path = matches.first().cloned().ok_or(Error::NotFound)?;
     Ok(())
 }
 
 #[rocket::put("/nar/<name>", data = "<data>")]
 async fn put_nar(
     conn: DbConn,
-    name: NarXzName<'_>,
+    name: NarName<'_>,
     data: rocket::Data<'_>,
     state: &rocket::State<Arc<State>>,
 ) -> Result<()> {
-    let url = format!("{}.nar.xz", name.0);
-    state.backend.write_nar(&url, &mut data.open(10.gigabytes())).await?;
-    add_incomplete(&conn, state, &url, IncompleteUpload::Nar).await?;
+    let compression = Compression::from_nar_name(name.0).ok_or(Error::BadNar("unknown compression"))?;
+    let url = name.0.to_string();
+    let object_url = format!("nar/{}", url);
+    // Nix pushes whole closures over and over, so the NAR is often stored already.
+    let measured = match db_run!(conn, |c| c.has_object(&object_url))? {
+        true => None,
+        false => Some(write_measured(state, &url, compression, data.open(10.gigabytes())).await?),
+    };
+    add_incomplete(&conn, state, &url, IncompleteUpload::Nar(measured)).await?;
     Ok(())
 }
 
+/// Writes an uploaded NAR to the backend while measuring it, so that it can be checked
+/// against its narinfo without reading it back.
+async fn write_measured<R: AsyncRead + Unpin>(
+    state: &State,
+    url: &str,
+    compression: Compression,
+    mut body: R,
+) -> Result<scrub::Measured> {
+    let (mut to_backend, mut object) = tokio::io::duplex(PIPE_SIZE);
+    let (mut to_measure, measured) = tokio::io::duplex(PIPE_SIZE);
+    let copy = async move {
+        let mut buf = vec![0; PIPE_SIZE];
+        loop {
+            let n = body.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<_, Error>(());
+            }
+            to_backend.write_all(&buf[..n]).await?;
+            to_measure.write_all(&buf[..n]).await?;
+        }
+    };
+    let write = state.backend.write_nar(url, &mut object);
+    let measure = scrub::Measured::measure(measured, compression);
+    match tokio::try_join!(copy, write, measure) {
+        Ok((_, _, measured)) => Ok(measured),
+        Err(e) => {
+            state.backend.abort_nar(url).await?;
+            Err(e)
+        }
+    }
+}
+
 async fn add_incomplete(
     conn: &DbConn,
     state: &rocket::State<Arc<State>>,
     url: &str,
     part: IncompleteUpload,
 ) -> Result<()> {
-    if let Some(nar_info) = {
+    if let Some((nar_info, references, measured)) = {
         let mut queued_uploads = state.queued_uploads.lock().await;
         match (part, queued_uploads.remove(&url.to_string())) {
-            (IncompleteUpload::Nar, Some(IncompleteUpload::NarInfo(nar_info))) => {
-                Some(nar_info)
+            (IncompleteUpload::Nar(measured), Some(IncompleteUpload::NarInfo(nar_info, references))) => {
+                Some((*nar_info, references, measured))
             }
-            (IncompleteUpload::NarInfo(nar_info), Some(IncompleteUpload::Nar)) => {
-                Some(nar_info)
+            (IncompleteUpload::NarInfo(nar_info, references), Some(IncompleteUpload::Nar(measured))) => {
+                Some((*nar_info, references, measured))
             }
             (part, _) => {
                 queued_uploads.insert(url.to_string(), part);
@@ -164,64 +363,818 @@ async fn add_incomplete(
             }
         }
     } {
-        complete_upload(conn, state, url, nar_info).await?;
+        complete_upload(conn, state, url, nar_info, references, measured).await?;
     }
     Ok(())
 }
 
-async fn complete_upload(conn: &DbConn, state: &rocket::State<Arc<State>>, url: &str, nar_info: DbPath) -> Result<()> {
-    state.backend.finish_nar(&url).await?;
-    conn.run(move |c| {
-        diesel::insert_into(paths)
-            .values(DbPath::from(nar_info))
-            .execute(c)
-    })
-    .await?;
+async fn complete_upload(
+    conn: &DbConn,
+    state: &State,
+    url: &str,
+    mut nar_info: DbPath,
+    references: BTreeSet<String>,
+    measured: Option<scrub::Measured>,
+) -> Result<()> {
+    let id = nar_info.id.clone();
+    let file_hash = nar_info.file_hash.clone();
+    let object_url = format!("nar/{}", url);
+    let new_path = nar_info.clone();
+    let new_refs = references.clone();
+    let own_url = object_url.clone();
+    let lookup = db_run!(conn, |c| {
+        // A path uploaded again keeps its object, and other paths reuse identical ones.
+        if let Some((cached, cached_refs)) = c.load_path(&id)? {
+            let cached_refs = cached_refs.into_iter().collect::<BTreeSet<_>>();
+            if let Some(mismatch) = fingerprint_mismatch(&cached, &cached_refs, &new_path, &new_refs) {
+                return Ok(Err(mismatch));
+            }
+            if let Some(url) = cached.url {
+                return Ok(Ok((true, Some(url))));
+            }
+        }
+        if c.has_object(&own_url)? {
+            return Ok(Ok((false, Some(own_url))));
+        }
+        match file_hash {
+            Some(file_hash) => c.find_object(&file_hash).map(|url| Ok((false, url))),
+            None => Ok::<_, diesel::result::Error>(Ok((false, None))),
+        }
+    })?;
+    // The stored NAR was checked against the cached fingerprint, so a known path only
+    // needs the same one.
+    let (known, existing) = match lookup {
+        Ok(found) => found,
+        Err(mismatch) => {
+            warn!("rejecting {}: {}", nar_info.path, mismatch);
+            state.backend.abort_nar(url).await?;
+            return Err(Error::InvalidUpload(mismatch));
+        }
+    };
+    let stored = existing.is_none();
+    match existing {
+        Some(existing) => {
+            state.backend.abort_nar(url).await?;
+            nar_info.url = Some(existing);
+        }
+        None => state.backend.finish_nar(url).await?,
+    }
+
+    // Refuse NARs that do not match their narinfo before they become substitutable.
+    if !known {
+        let problems = match measured.map(|measured| measured.problems(&nar_info)).transpose()?.flatten() {
+            Some(problems) => problems,
+            // Stored before this upload, or hashed with something other than SHA-256.
+            None => scrub::check_object(conn, &*state.backend, &nar_info, true).await?,
+        };
+        if !problems.is_empty() {
+            for problem in &problems {
+                warn!("rejecting {}: {}", nar_info.path, problem);
+            }
+            if stored {
+                state.backend.delete_nar(url).await?;
+            }
+            let problems = problems.iter().map(ToString::to_string).collect::<Vec<_>>();
+            return Err(Error::InvalidUpload(problems.join(", ")));
+        }
+    }
+
+    let chunked = stored && state.chunking.enabled;
+    if chunked {
+        let compression = nar_info.compression()?;
+        if let Err(e) = chunks::store_chunked(conn, &*state.backend, &state.chunking, &object_url, &compression).await {
+            state.backend.delete_nar(url).await?;
+            return Err(e);
+        }
+    }
+    nar_info.registration_time = Some(now());
+    let db_path = nar_info.clone();
+    // Unless the NAR was stored just now, the path shares an object looked up earlier.
+    let inserted = db_run!(conn, |c| c.upsert_path(&nar_info, &references, !stored));
+    if stored && !matches!(inserted, Ok(true)) {
+        // No path refers to the new object.
+        chunks::delete_object(conn, &*state.backend, &object_url).await?;
+    }
+    if chunked {
+        // Served from its chunks from now on, if at all.
+        state.backend.delete_nar(url).await?;
+    }
+    if inserted? {
+        listing::try_store_listing(conn, &*state.backend, &db_path).await;
+    } else {
+        info!("{} already exists, merged its signatures", db_path.path);
+    }
+    if stored {
+        state.wake_gc_when_low().await;
+    }
     Ok(())
 }
 
 #[derive(Debug)]
 pub enum IncompleteUpload {
-    Nar,
-    NarInfo(DbPath),
+    /// A NAR, measured unless its object was stored already.
+    Nar(Option<scrub::Measured>),
+    NarInfo(Box<DbPath>, BTreeSet<String>),
 }
 
 struct State {
     queued_uploads: Mutex<BTreeMap<String, IncompleteUpload>>,
-    backend: Box<dyn Backend + Send + Sync>,
+    backend: Arc<dyn Backend + Send + Sync>,
+    access_log: Option<TraceWriter>,
+    chunking: ChunkingConfig,
+    compression: Compression,
+    accesses: Accesses,
+    /// Starts a garbage collection ahead of `gc.interval`.
+    gc_wakeup: Notify,
 }
 
-#[rocket::launch]
-async fn rocket() -> _ {
-    let backend = {
-        let bucket_name = "yuka-testbucket";
-        let region = "eu-central-1".parse().unwrap();
-        let credentials = Credentials::default().unwrap();
-        let bucket = Bucket::new(bucket_name, region, credentials).unwrap();
+impl State {
+    /// Wakes the garbage collector once an upload took the disk below its low watermark,
+    /// rather than waiting for the next interval while the disk fills up.
+    async fn wake_gc_when_low(&self) {
+        match self.backend.space_to_free().await {
+            Ok(0) => (),
+            Ok(_) => self.gc_wakeup.notify_one(),
+            Err(e) => warn!("failed to check free space: {}", e),
+        }
+    }
+}
 
-        /*let mut reader = Cursor::new("foo");
-        let path = String::from("foo");
-        bucket.put_object_stream(&mut reader, path).await.unwrap();*/
+#[derive(Parser)]
+#[clap(about, version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
 
-        Box::new(bucket)
-        //Box::new(LocalBackend::new_current_dir().unwrap())
-    };
-    let state = Arc::new(State {
-        queued_uploads: Default::default(),
-        backend,
-    });
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the binary cache server (the default)
+    Serve,
+    /// Verify every stored NAR against the database
+    Scrub(scrub::ScrubOptions),
+    /// Find backend objects that no path refers to
+    Orphans(orphans::OrphanOptions),
+    /// Evict expired paths, then more until the cache fits into gc.max_size
+    Gc(gc::GcOptions),
+    /// Protect a path and its closure from garbage collection
+    Pin(PinOptions),
+    /// Remove the pin on a path
+    Unpin {
+        /// Store path or hash
+        path: String,
+    },
+    /// List all pins
+    Pins,
+    /// Replay an access log against eviction policies and cache budgets
+    Simulate(simulate::SimulateOptions),
+    /// Import the output of nix-store --export
+    Import {
+        /// File to read instead of the standard input
+        file: Option<PathBuf>,
+    },
+}
 
-    rocket::build()
-        .manage(state)
+#[derive(clap::Args)]
+struct PinOptions {
+    /// Store path or hash
+    path: String,
+    /// Let the pin expire after this long
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    expires: Option<Duration>,
+    /// Why the path is pinned
+    #[clap(long)]
+    note: Option<String>,
+}
+
+fn rocket() -> Rocket<Build> {
+    build(rocket::Config::figment())
+}
+
+fn build(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
         .attach(DbConn::fairing())
+        .attach(AdHoc::try_on_ignite("Backend", |rocket| async {
+            let config = match rocket.figment().extract::<Config>() {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("invalid configuration: {}", e);
+                    return Err(rocket);
+                }
+            };
+            if !config.chunking.is_valid() {
+                error!("invalid chunk sizes {:?}", config.chunking);
+                return Err(rocket);
+            }
+            let backend = match config.backend.build() {
+                Ok(backend) => Arc::from(backend),
+                Err(e) => {
+                    error!("invalid backend configuration: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let access_log = match &config.access_log {
+                Some(path) => match TraceWriter::open(path).await {
+                    Ok(access_log) => Some(access_log),
+                    Err(e) => {
+                        error!("failed to open access log {}: {}", path.display(), e);
+                        return Err(rocket);
+                    }
+                },
+                None => None,
+            };
+            Ok(rocket
+                .manage(Arc::new(State {
+                    queued_uploads: Default::default(),
+                    backend,
+                    access_log,
+                    chunking: config.chunking.clone(),
+                    compression: config.compression.clone(),
+                    accesses: Default::default(),
+                    gc_wakeup: Notify::new(),
+                }))
+                .manage(config))
+        }))
+        .attach(AdHoc::on_liftoff("Access Times", |rocket| Box::pin(async move {
+            let state = rocket.state::<Arc<State>>().expect("state is managed").clone();
+            let conn = match DbConn::get_one(rocket).await {
+                Some(conn) => conn,
+                None => {
+                    error!("no database connection for recording access times");
+                    return;
+                }
+            };
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(access::FLUSH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = state.accesses.flush(&conn).await {
+                        error!("failed to record access times: {}", e);
+                    }
+                }
+            });
+        })))
+        .attach(AdHoc::on_liftoff("Garbage Collector", |rocket| Box::pin(async move {
+            let config = rocket.state::<Config>().expect("config is managed");
+            if !config.gc_enabled() {
+                return;
+            }
+            let config = config.gc.clone();
+            let state = rocket.state::<Arc<State>>().expect("state is managed").clone();
+            // The collector keeps one pooled connection for the lifetime of the server.
+            let conn = match DbConn::get_one(rocket).await {
+                Some(conn) => conn,
+                None => {
+                    error!("no database connection for the garbage collector");
+                    return;
+                }
+            };
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(config.interval);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => (),
+                        _ = state.gc_wakeup.notified() => info!("low on disk space, collecting early"),
+                    }
+                    // Collections rank paths by the access times in the database.
+                    if let Err(e) = state.accesses.flush(&conn).await {
+                        error!("failed to record access times: {}", e);
+                    }
+                    match gc::collect(&conn, &*state.backend, &config, false).await {
+                        Ok(stats) => info!(
+                            "garbage collection evicted {} paths ({} bytes), {} bytes pinned",
+                            stats.evicted_paths, stats.evicted_bytes, stats.pinned_bytes,
+                        ),
+                        Err(e) => error!("garbage collection failed: {}", e),
+                    }
+                }
+            });
+        })))
         .mount(
             "/",
             rocket::routes![
                 nix_cache_info,
                 get_narinfo,
+                get_listing,
                 put_narinfo,
+                delete_narinfo,
                 get_nar,
+                head_nar,
                 put_nar,
             ],
         )
+        .mount("/api", api::routes())
+        .mount("/browse", browse::routes())
+        .mount("/", web::routes())
+}
+
+#[rocket::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            rocket().launch().await?;
+        }
+        Command::Scrub(options) => {
+            let (rocket, conn) = ignite_offline().await?;
+            let state = rocket.state::<Arc<State>>().expect("state is managed");
+            let report = scrub::scrub(&conn, &*state.backend, &options).await?;
+            for (db_path, problems) in &report.bad {
+                for problem in problems {
+                    println!("{} {}: {}", db_path.id, db_path.path, problem);
+                }
+            }
+            println!("checked {} paths, {} bad", report.checked, report.bad.len());
+            if report.failed > 0 {
+                anyhow::bail!("{:?} failed on {} paths", options.action, report.failed);
+            }
+        }
+        Command::Orphans(options) => {
+            let (rocket, conn) = ignite_offline().await?;
+            let state = rocket.state::<Arc<State>>().expect("state is managed");
+            let report = orphans::find_orphans(&conn, &*state.backend, &options).await?;
+            for object in &report.data {
+                println!("data/{} ({} bytes)", object.url, object.size);
+            }
+            for object in &report.tmp {
+                println!("tmp/{} ({} bytes)", object.url, object.size);
+            }
+            let verb = if options.delete { "deleted" } else { "found" };
+            println!("{} {} orphaned objects and {} abandoned uploads", verb, report.data.len(), report.tmp.len());
+        }
+        Command::Gc(options) => {
+            let (rocket, conn) = ignite_offline().await?;
+            let state = rocket.state::<Arc<State>>().expect("state is managed");
+            let config = rocket.state::<Config>().expect("config is managed");
+            if !config.gc_enabled() {
+                anyhow::bail!("none of gc.max_size, gc.max_idle, gc.max_age and backend.watermarks is configured");
+            }
+            let config = &config.gc;
+            let stats = gc::collect(&conn, &*state.backend, config, options.dry_run).await?;
+            let verb = if options.dry_run { "would evict" } else { "evicted" };
+            println!(
+                "{} {} paths ({} bytes) of {} bytes, {} bytes pinned",
+                verb, stats.evicted_paths, stats.evicted_bytes, stats.total_bytes, stats.pinned_bytes,
+            );
+        }
+        Command::Pin(options) => {
+            let (_rocket, conn) = ignite_offline().await?;
+            let expires = options
+                .expires
+                .map(|duration| (SystemTime::now() + duration).duration_since(SystemTime::UNIX_EPOCH))
+                .transpose()?
+                .map(|time| time.as_secs() as i64);
+            let pin = DbPin {
+                id: gc::path_id(&options.path).to_string(),
+                created: now(),
+                expires,
+                note: options.note,
+            };
+            let exists = db_run!(conn, |c| {
+                c.load_path(&pin.id).and_then(|path| match path {
+                    Some(_) => c.pin(&pin).map(|_| true),
+                    None => Ok(false),
+                })
+            })?;
+            if !exists {
+                anyhow::bail!("{} is not in the cache", options.path);
+            }
+        }
+        Command::Unpin { path } => {
+            let (_rocket, conn) = ignite_offline().await?;
+            let id = gc::path_id(&path).to_string();
+            if db_run!(conn, |c| c.unpin(&id))? == 0 {
+                anyhow::bail!("{} is not pinned", path);
+            }
+        }
+        Command::Pins => {
+            let (_rocket, conn) = ignite_offline().await?;
+            for pin in db_run!(conn, |c| c.pins())? {
+                let expires = pin.expires.map_or("never".to_string(), |expires| expires.to_string());
+                println!("{} expires={} {}", pin.id, expires, pin.note.unwrap_or_default());
+            }
+        }
+        Command::Import { file } => {
+            let (rocket, conn) = ignite_offline().await?;
+            let state = rocket.state::<Arc<State>>().expect("state is managed");
+            let imported = match file {
+                Some(file) => import::import(&conn, state, tokio::fs::File::open(file).await?).await,
+                None => import::import(&conn, state, tokio::io::stdin()).await,
+            };
+            match imported {
+                Ok(imported) => println!("imported {} paths", imported.len()),
+                Err(Error::PartialImport { imported, source }) => {
+                    for path in imported {
+                        println!("imported {}", path);
+                    }
+                    return Err((*source).into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Command::Simulate(options) => {
+            let config = rocket::Config::figment().extract::<Config>()?.gc;
+            let records = simulate::read_trace(&options.trace)?;
+            let max_sizes = match (options.max_sizes.is_empty(), config.max_size) {
+                (false, _) => options.max_sizes,
+                (true, Some(max_size)) => vec![max_size],
+                (true, None) => anyhow::bail!("no --max-size given and gc.max_size is not configured"),
+            };
+            let policies = match options.policies.is_empty() {
+                false => options.policies,
+                true => vec![PolicyKind::Lru, PolicyKind::Lfu, PolicyKind::Gdsf],
+            };
+            println!("{:<6} {:>12} {:>10} {:>15} {:>10}", "policy", "max size", "hit ratio", "byte hit ratio", "evicted");
+            for policy in &policies {
+                for max_size in &max_sizes {
+                    let config = GcConfig { max_size: Some(*max_size), policy: *policy, ..config.clone() };
+                    let report = simulate::simulate(&records, &config);
+                    println!(
+                        "{:<6} {:>12} {:>10.4} {:>15.4} {:>10}",
+                        policy.name(),
+                        max_size.to_string(),
+                        report.hit_ratio(),
+                        report.byte_hit_ratio(),
+                        report.evicted_paths,
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets up the database and backend like the server does, without launching it.
+async fn ignite_offline() -> anyhow::Result<(Rocket<rocket::Ignite>, DbConn)> {
+    let rocket = rocket().ignite().await?;
+    let conn = DbConn::get_one(&rocket).await.ok_or_else(|| anyhow::anyhow!("no database connection"))?;
+    Ok((rocket, conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nixutils::nar::NarWriter;
+    use nixutils::{HashType, HashingReader};
+    use rocket::http::{Accept, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use tokio::io::AsyncReadExt;
+
+    /// Where the test named `name` keeps its database and backend.
+    pub(crate) fn test_root(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nyancache-{}-{}", name, std::process::id()))
+    }
+
+    /// A server on a fresh SQLite database and local backend, named after the test.
+    pub(crate) async fn client(name: &str, chunking: bool) -> Client {
+        let root = test_root(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let figment = rocket::Config::figment()
+            .merge(("log_level", "off"))
+            .merge(("databases.sqlite_nyancache.url", root.join("db.sqlite")))
+            .merge(("admin_token", "secret"))
+            .merge(("backend", serde_json::json!({
+                "type": "local",
+                "tmp_dir": root.join("tmp"),
+                "data_dir": root.join("data"),
+                "quarantine_dir": root.join("quarantine"),
+            })))
+            .merge(("chunking", serde_json::json!({
+                "enabled": chunking,
+                "min_size": 64,
+                "avg_size": 256,
+                "max_size": 1024,
+            })));
+        Client::tracked(build(figment)).await.unwrap()
+    }
+
+    /// Uploads a NAR with its narinfo like `nix copy` does, and returns its url.
+    pub(crate) async fn upload(client: &Client, path: &str, nar: &[u8], references: &[&str]) -> String {
+        let (nar_info, file) = compress(path, nar, references, Compression::Xz).await;
+        let url = nar_info.url.clone().unwrap();
+        let response = client.put(format!("/{}", url)).body(file).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let id = gc::path_id(path);
+        let response = client.put(format!("/{}.narinfo", id)).body(nar_info.to_string()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        url
+    }
+
+    /// Compresses a NAR like `nix copy` does, and returns its narinfo and the object.
+    async fn compress(path: &str, nar: &[u8], references: &[&str], compression: Compression) -> (NarInfo, Vec<u8>) {
+        let mut hashing = HashingReader::new(nar, HashType::Sha256).unwrap();
+        let mut file = Vec::new();
+        compression.encoder(&mut hashing).read_to_end(&mut file).await.unwrap();
+        let (nar_hash, nar_size) = hashing.finish();
+        let mut hashing = HashingReader::new(&file[..], HashType::Sha256).unwrap();
+        tokio::io::copy(&mut hashing, &mut tokio::io::sink()).await.unwrap();
+        let (file_hash, file_size) = hashing.finish();
+        let url = format!("nar/{}{}", file_hash.to_string().trim_start_matches("sha256:"), compression.nar_extension());
+        let nar_info = NarInfo {
+            path: path.to_string(),
+            nar_hash,
+            nar_size,
+            file_hash: Some(file_hash),
+            file_size: Some(file_size),
+            url: Some(url),
+            compression: Some(compression),
+            deriver: None,
+            ca: None,
+            references: references.iter().map(ToString::to_string).collect(),
+            signatures: Default::default(),
+        };
+        (nar_info, file)
+    }
+
+    /// A NAR of a single file.
+    pub(crate) async fn file_nar(contents: &[u8]) -> Vec<u8> {
+        let mut writer = NarWriter::new(Vec::new());
+        writer.file("", false, contents.len() as u64, contents).await.unwrap();
+        writer.finish().await.unwrap()
+    }
+
+    /// A page of HTML, a large file filling a few chunks, and links to both, next to a dotfile.
+    async fn sample_nar(padding: usize) -> Vec<u8> {
+        let large = (0..padding).map(|n| (n * 7919 % 251) as u8).collect::<Vec<_>>();
+        let mut writer = NarWriter::new(Vec::new());
+        writer.directory("").await.unwrap();
+        writer.file("large", false, large.len() as u64, &large[..]).await.unwrap();
+        writer.directory("share").await.unwrap();
+        writer.file("share/.hidden", false, 7, &b"dotfile"[..]).await.unwrap();
+        writer.symlink("share/home.html", "index.html").await.unwrap();
+        writer.file("share/index.html", false, 31, &b"<script>alert('hi')</script>\n\n\n"[..]).await.unwrap();
+        writer.finish().await.unwrap()
+    }
+
+    async fn check_browse(name: &str, chunking: bool) {
+        let client = client(name, chunking).await;
+        let id = "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0";
+        let nar = sample_nar(20_000).await;
+        upload(&client, &format!("/nix/store/{}-site", id), &nar, &[]).await;
+        let stats = client.get("/api/chunks/stats").dispatch().await.into_string().await.unwrap();
+        let stats = serde_json::from_str::<serde_json::Value>(&stats).unwrap();
+        assert_eq!(stats["chunks"].as_i64().unwrap() > 1, chunking);
+
+        let response = client.get(format!("/browse/{}/share/index.html", id)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(response.headers().get_one("Content-Security-Policy"), Some("sandbox"));
+        assert_eq!(response.into_string().await.unwrap(), "<script>alert('hi')</script>\n\n\n");
+
+        let response = client.get(format!("/browse/{}/share", id)).header(Accept::JSON).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let listing = serde_json::from_str::<listing::ListingNode>(&response.into_string().await.unwrap()).unwrap();
+        assert!(listing.lookup(["index.html"]).is_some());
+        assert!(listing.lookup(["home.html"]).is_some());
+
+        let response = client.get(format!("/browse/{}/share/.hidden", id)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), "dotfile");
+        let response = client.get(format!("/browse/{}/share/%2E%2E/large", id)).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client.get(format!("/browse/{}/share/home.html", id)).dispatch().await;
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some(format!("/browse/{}/share/index.html", id).as_str()));
+        std::fs::remove_dir_all(test_root(name)).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn browse() {
+        check_browse("browse", false).await;
+    }
+
+    #[rocket::async_test]
+    async fn browse_chunked() {
+        check_browse("browse-chunked", true).await;
+    }
+
+    #[rocket::async_test]
+    async fn missing() {
+        let client = client("missing", false).await;
+        let cached = format!("/nix/store/{:0>32}-test", "cached");
+        upload(&client, &cached, &file_nar(b"cached").await, &[]).await;
+        let query = |count: usize| {
+            let mut store_paths = (1..count).map(|n| format!("/nix/store/{:0>32}-test", n)).collect::<Vec<_>>();
+            store_paths.push(cached.clone());
+            client.post("/api/missing").header(ContentType::JSON).body(serde_json::to_string(&store_paths).unwrap())
+        };
+
+        // More than fits into Rocket's default JSON limit.
+        let response = query(30_000).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let uncached = serde_json::from_str::<Vec<String>>(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(uncached.len(), 29_999);
+        assert!(!uncached.contains(&cached));
+
+        let response = query(30_001).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let error = serde_json::from_str::<serde_json::Value>(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(error["errors"][0]["code"], "TooManyPaths");
+        std::fs::remove_dir_all(test_root("missing")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn rejects_bad_uploads() {
+        let client = client("rejects", false).await;
+        let path = format!("/nix/store/{:0>32}-test", "a");
+        let id = gc::path_id(&path);
+        let nar = file_nar(b"contents").await;
+        let data = test_root("rejects").join("data");
+
+        let (nar_info, file) = compress(&path, &nar, &[], Compression::Xz).await;
+        let (other, _) = compress(&path, &file_nar(b"other").await, &[], Compression::Xz).await;
+        let wrong_hash = (NarInfo { nar_hash: other.nar_hash, ..nar_info.clone() }, file.clone());
+        let truncated = (nar_info, file[..file.len() / 2].to_vec());
+        let (nar_info, file) = compress(&path, &nar, &[], Compression::Zstd).await;
+        let wrong_compression = (NarInfo { compression: Some(Compression::Xz), ..nar_info }, file);
+        for (nar_info, file) in [wrong_hash, truncated, wrong_compression] {
+            let url = nar_info.url.clone().unwrap();
+            let response = client.put(format!("/{}", url)).body(file).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let response = client.put(format!("/{}.narinfo", id)).body(nar_info.to_string()).dispatch().await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.get(format!("/{}.narinfo", id)).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+            assert!(!data.join(models::backend_url(&url).unwrap()).exists());
+        }
+        std::fs::remove_dir_all(test_root("rejects")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn compressions() {
+        let client = client("compressions", false).await;
+        for (n, compression) in [Compression::Plain, Compression::Bzip2, Compression::Zstd].into_iter().enumerate() {
+            let path = format!("/nix/store/{:0>32}-test", n);
+            let nar = file_nar(compression.as_ref().as_bytes()).await;
+            let (nar_info, file) = compress(&path, &nar, &[], compression.clone()).await;
+            let response = client.put(format!("/{}", nar_info.url.as_deref().unwrap())).body(file).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let response = client.put(format!("/{}.narinfo", gc::path_id(&path))).body(nar_info.to_string()).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let (served, served_nar) = download(&client, &path).await;
+            assert_eq!(served.compression, Some(compression));
+            assert_eq!(served_nar, nar);
+        }
+        let response = client.put(format!("/nar/{:0>52}.nar.lz4", 0)).body("").dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+        std::fs::remove_dir_all(test_root("compressions")).unwrap();
+    }
+
+    /// The narinfo of a path and its NAR, as a client of the cache sees them.
+    async fn download(client: &Client, path: &str) -> (NarInfo, Vec<u8>) {
+        let response = client.get(format!("/{}.narinfo", gc::path_id(path))).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let nar_info = NarInfo::from_str(&response.into_string().await.unwrap()).unwrap();
+        let response = client.get(format!("/{}", nar_info.url.as_deref().unwrap())).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let file = response.into_bytes().await.unwrap();
+        let mut nar = Vec::new();
+        let compression = nar_info.compression.clone().unwrap_or_default();
+        compression.decoder(&file[..]).read_to_end(&mut nar).await.unwrap();
+        (nar_info, nar)
+    }
+
+    #[rocket::async_test]
+    async fn export_import() {
+        let source = client("export", true).await;
+        let target = client("import", false).await;
+        let [lib, app] = ["lib", "app"].map(|name| format!("/nix/store/{:0>32}-{}", name, name));
+        let nars = [file_nar(b"library").await, sample_nar(5_000).await];
+        upload(&source, &lib, &nars[0], &[&lib]).await;
+        upload(&source, &app, &nars[1], &[&app, &lib]).await;
+
+        let response = source.get(format!("/api/export/{}", gc::path_id(&app))).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let export = response.into_bytes().await.unwrap();
+        let admin = Header::new("Authorization", "Bearer secret");
+        let response = target.post("/api/import").header(admin).body(export).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let imported = serde_json::from_str::<Vec<String>>(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(imported, vec![lib.clone(), app.clone()]);
+
+        for (path, nar) in [lib, app].iter().zip(&nars) {
+            let (exported, exported_nar) = download(&source, path).await;
+            let (imported, imported_nar) = download(&target, path).await;
+            assert_eq!(imported.path, exported.path);
+            assert_eq!(imported.nar_hash, exported.nar_hash);
+            assert_eq!(imported.nar_size, exported.nar_size);
+            assert_eq!(imported.references, exported.references);
+            assert_eq!(imported.deriver, exported.deriver);
+            assert_eq!(&exported_nar, nar);
+            assert_eq!(&imported_nar, nar);
+        }
+        std::fs::remove_dir_all(test_root("export")).unwrap();
+        std::fs::remove_dir_all(test_root("import")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn delete_shared_object() {
+        let client = client("delete-shared", false).await;
+        let [a, b] = ["a", "b"].map(|name| format!("{:0>32}", name));
+        let nar = file_nar(b"shared").await;
+        let url = upload(&client, &format!("/nix/store/{}-test", a), &nar, &[]).await;
+        assert_eq!(upload(&client, &format!("/nix/store/{}-test", b), &nar, &[]).await, url);
+        let object = test_root("delete-shared").join("data").join(models::backend_url(&url).unwrap());
+        let admin = Header::new("Authorization", "Bearer secret");
+
+        // The object stays as long as a path refers to it.
+        let response = client.delete(format!("/{}.narinfo", a)).header(admin.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(object.exists());
+        assert_eq!(client.get(format!("/{}", url)).dispatch().await.status(), Status::Ok);
+
+        let response = client.delete(format!("/{}.narinfo", b)).header(admin).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!object.exists());
+        assert_eq!(client.get(format!("/{}", url)).dispatch().await.status(), Status::NotFound);
+        std::fs::remove_dir_all(test_root("delete-shared")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn gc_wakes_up_when_low() {
+        use backend::local::{LocalBackend, Watermarks};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let root = test_root("gc-wakeup");
+        let free = Arc::new(AtomicU64::new(1000));
+        let measured = free.clone();
+        let watermarks = Watermarks { low: 100.bytes(), high: 300.bytes(), critical: 50.bytes() };
+        let backend = LocalBackend::new(root.join("tmp"), root.join("data"), root.join("quarantine"))
+            .with_watermarks(Some(watermarks))
+            .with_free_space(Arc::new(move |_| Ok(measured.load(Ordering::SeqCst))));
+        let state = State {
+            queued_uploads: Default::default(),
+            backend: Arc::new(backend),
+            access_log: None,
+            chunking: Default::default(),
+            compression: Default::default(),
+            accesses: Default::default(),
+            gc_wakeup: Notify::new(),
+        };
+        let woken = || tokio::time::timeout(Duration::from_millis(10), state.gc_wakeup.notified());
+
+        state.wake_gc_when_low().await;
+        assert!(woken().await.is_err());
+        free.store(80, Ordering::SeqCst);
+        state.wake_gc_when_low().await;
+        assert!(woken().await.is_ok());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn accesses() {
+        let client = client("accesses", false).await;
+        let id = "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0";
+        let url = upload(&client, &format!("/nix/store/{}-hello", id), &file_nar(b"hello").await, &[]).await;
+        assert_eq!(client.get(format!("/{}.narinfo", id)).dispatch().await.status(), Status::Ok);
+        for _ in 0..2 {
+            assert_eq!(client.get(format!("/{}", url)).dispatch().await.status(), Status::Ok);
+        }
+
+        let conn = DbConn::get_one(client.rocket()).await.unwrap();
+        let key = id.to_string();
+        let (db_path, _) = db_run!(conn, |c| c.load_path(&key)).unwrap().unwrap();
+        assert_eq!((db_path.last_accessed, db_path.hit_count), (None, 0));
+        client.rocket().state::<Arc<State>>().unwrap().accesses.flush(&conn).await.unwrap();
+        let key = id.to_string();
+        let (db_path, _) = db_run!(conn, |c| c.load_path(&key)).unwrap().unwrap();
+        assert!(db_path.last_accessed.is_some());
+        assert_eq!(db_path.hit_count, 2);
+        std::fs::remove_dir_all(test_root("accesses")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn delete() {
+        let client = client("delete", false).await;
+        let [app, lib] = ["app", "lib"].map(|name| format!("{:0>32}", name));
+        let [app_path, lib_path] = [&app, &lib].map(|id| format!("/nix/store/{}-test", id));
+        upload(&client, &lib_path, &file_nar(b"lib").await, &[]).await;
+        upload(&client, &app_path, &file_nar(b"app").await, &[&app_path, &lib_path]).await;
+        let admin = Header::new("Authorization", "Bearer secret");
+        // Pins take full store paths as well as hashes.
+        let response = client.put(format!("/api/pins/%2Fnix%2Fstore%2F{}-test", lib)).header(admin.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let pins = client.get("/api/pins").dispatch().await.into_string().await.unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&pins).unwrap()[0]["id"], lib.as_str());
+        let delete = |id: &str, query: &str| client.delete(format!("/{}.narinfo{}", id, query)).header(admin.clone());
+
+        let response = client.delete(format!("/{}.narinfo", lib)).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(delete(&lib, "?only_unreferenced=true").dispatch().await.status(), Status::Conflict);
+        // A path referencing itself is still unreferenced.
+        assert_eq!(delete(&app, "?only_unreferenced=true").dispatch().await.status(), Status::Ok);
+        assert_eq!(delete(&lib, "?only_unreferenced=true").dispatch().await.status(), Status::Ok);
+        assert_eq!(delete(&lib, "").dispatch().await.status(), Status::NotFound);
+
+        for id in [&app, &lib] {
+            assert_eq!(client.get(format!("/{}.narinfo", id)).dispatch().await.status(), Status::NotFound);
+        }
+        let pins = client.get("/api/pins").dispatch().await.into_string().await.unwrap();
+        assert_eq!(pins, "[]");
+        assert_eq!(std::fs::read_dir(test_root("delete").join("data")).unwrap().count(), 0);
+        std::fs::remove_dir_all(test_root("delete")).unwrap();
+    }
 }
@@ -0,0 +1,63 @@
+use crate::error::{Error, Result};
+
+use diesel::pg::PgConnection;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::{setup_database, MigrationConnection, RunMigrationsError};
+
+mod sqlite {
+    embed_migrations!("migrations/sqlite");
+    pub use embedded_migrations::run;
+
+    pub const VERSIONS: &[&str] = include!(concat!(env!("OUT_DIR"), "/sqlite_versions.rs"));
+}
+
+mod postgres {
+    embed_migrations!("migrations/postgres");
+    pub use embedded_migrations::run;
+
+    pub const VERSIONS: &[&str] = include!(concat!(env!("OUT_DIR"), "/postgres_versions.rs"));
+}
+
+/// A connection type with its set of migrations compiled into the binary.
+pub trait EmbeddedMigrations: MigrationConnection {
+    fn embedded_versions() -> &'static [&'static str];
+    fn run_embedded(&self) -> std::result::Result<(), RunMigrationsError>;
+}
+
+impl EmbeddedMigrations for SqliteConnection {
+    fn embedded_versions() -> &'static [&'static str] {
+        sqlite::VERSIONS
+    }
+    fn run_embedded(&self) -> std::result::Result<(), RunMigrationsError> {
+        sqlite::run(self)
+    }
+}
+
+impl EmbeddedMigrations for PgConnection {
+    fn embedded_versions() -> &'static [&'static str] {
+        postgres::VERSIONS
+    }
+    fn run_embedded(&self) -> std::result::Result<(), RunMigrationsError> {
+        postgres::run(self)
+    }
+}
+
+/// Applies all embedded migrations that have not been run on the database yet.
+///
+/// Refuses to touch a database that has migrations applied which this build does not know
+/// about, as it was most likely set up by a newer version.
+pub fn run<C: EmbeddedMigrations>(conn: &C) -> Result<()> {
+    setup_database(conn)?;
+    let known = C::embedded_versions();
+    let mut unknown = conn
+        .previously_run_migration_versions()?
+        .into_iter()
+        .filter(|version| !known.contains(&version.as_str()))
+        .collect::<Vec<_>>();
+    unknown.sort();
+    if let Some(newest) = unknown.pop() {
+        return Err(Error::SchemaTooNew(newest));
+    }
+    conn.run_embedded()?;
+    Ok(())
+}
@@ -1,41 +1,142 @@
+// diesel's Identifiable derive expands to a unit expression
+#![allow(clippy::unused_unit)]
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::db::{impl_for_connections, Dialect};
+use super::error::Error;
+use super::gc::GcQueries;
 use super::nixutils::{Compression, NarInfo, NixHash, Signature};
-use super::schema::paths;
+use super::schema::paths::{self, dsl::paths as all_paths};
+use super::schema::refs::{self, dsl::refs as all_refs};
+use super::schema::objects::{self, dsl::objects as all_objects};
+use super::schema::{gc_runs, pins};
 
-use diesel_derives::{Insertable, Queryable};
+use diesel::sql_types::{BigInt, Text};
+use diesel::{
+    Connection, EscapeExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, QueryResult, RunQueryDsl,
+    TextExpressionMethods,
+};
+use diesel_derives::{Insertable, Queryable, QueryableByName};
 use serde::Serialize;
 
-#[derive(Clone, Debug, Default, Queryable, Serialize, Insertable, Identifiable)]
+#[derive(Clone, Debug, Default, Queryable, QueryableByName, Serialize, Insertable, Identifiable)]
 #[table_name = "paths"]
 #[primary_key("id")]
 pub struct DbPath {
     pub id: String,
-    path: String,
-    registration_time: Option<i64>,
-    last_accessed: Option<i64>,
-    nar_size: i32,
-    nar_hash: String,
-    file_size: Option<i32>,
-    file_hash: Option<String>,
+    pub path: String,
+    pub registration_time: Option<i64>,
+    pub last_accessed: Option<i64>,
+    pub nar_size: i64,
+    pub nar_hash: String,
+    pub file_size: Option<i64>,
+    pub file_hash: Option<String>,
     pub url: Option<String>,
-    compression: Option<String>,
-    deriver: Option<String>,
-    ca: Option<String>,
-    sigs: String,
-    refs: String,
+    pub compression: Option<String>,
+    pub deriver: Option<String>,
+    pub ca: Option<String>,
+    pub sigs: String,
+    /// How often the NAR was downloaded.
+    pub hit_count: i64,
+    /// The GDSF inflation value when the path was last used.
+    pub inflation: f64,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "refs"]
+pub struct DbRef {
+    pub referrer: String,
+    pub reference: String,
+}
+
+/// A backend object, shared by all paths whose `url` points to it.
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "objects"]
+pub struct DbObject {
+    pub url: String,
+    pub file_hash: Option<String>,
+    pub refcount: i64,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable, Serialize)]
+#[table_name = "pins"]
+pub struct DbPin {
+    pub id: String,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub note: Option<String>,
+}
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct DbGcRun {
+    pub id: i64,
+    pub started: i64,
+    pub finished: i64,
+    pub total_bytes: i64,
+    pub pinned_bytes: i64,
+    pub evicted_paths: i64,
+    pub evicted_bytes: i64,
+    pub inflation: f64,
+}
+
+#[derive(Clone, Debug, Default, Insertable)]
+#[table_name = "gc_runs"]
+pub struct NewGcRun {
+    pub started: i64,
+    pub finished: i64,
+    pub total_bytes: i64,
+    pub pinned_bytes: i64,
+    pub evicted_paths: i64,
+    pub evicted_bytes: i64,
+    /// The GDSF inflation value after the collection.
+    pub inflation: f64,
+}
+
+/// Requests for a path since its row was last updated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Access {
+    pub last_accessed: i64,
+    /// How often the NAR was downloaded.
+    pub hits: i64,
 }
 
-impl From<NarInfo> for DbPath {
-    fn from(nar_info: NarInfo) -> Self {
-        Self {
+#[derive(Debug, Default, QueryableByName, Serialize)]
+pub struct Usage {
+    #[sql_type = "BigInt"]
+    pub paths: i64,
+    #[sql_type = "BigInt"]
+    pub nar_bytes: i64,
+    /// Bytes of compressed NARs, before deduplication.
+    #[sql_type = "BigInt"]
+    pub file_bytes: i64,
+}
+
+#[derive(Debug, QueryableByName)]
+struct ReferenceRow {
+    #[sql_type = "Text"]
+    reference: String,
+}
+
+impl TryFrom<NarInfo> for DbPath {
+    type Error = Error;
+
+    fn try_from(nar_info: NarInfo) -> Result<Self, Self::Error> {
+        Ok(Self {
             id: "".to_string(),
             path: nar_info.path,
             registration_time: None,
             last_accessed: None,
-            nar_size: nar_info.nar_size as i32,
+            nar_size: i64::try_from(nar_info.nar_size).map_err(|_| Error::SizeOutOfRange)?,
             nar_hash: nar_info.nar_hash.to_string(),
-            file_size: nar_info.file_size.map(|x| x as i32),
+            file_size: nar_info
+                .file_size
+                .map(i64::try_from)
+                .transpose()
+                .map_err(|_| Error::SizeOutOfRange)?,
             file_hash: nar_info.file_hash.map(|x| x.to_string()),
             url: nar_info.url,
             compression: nar_info.compression.map(|x| x.as_ref().to_string()),
@@ -53,35 +154,367 @@ impl From<NarInfo> for DbPath {
                 })
                 .collect::<Vec<_>>()
                 .join(" "),
-            refs: nar_info
-                .references
-                .into_iter()
-                .collect::<Vec<_>>()
-                .join(" "),
-        }
+            hit_count: 0,
+            inflation: 0.0,
+        })
     }
 }
-impl Into<NarInfo> for DbPath {
-    fn into(self) -> NarInfo {
+impl From<(DbPath, Vec<String>)> for NarInfo {
+    fn from((db_path, references): (DbPath, Vec<String>)) -> Self {
         NarInfo {
-            path: self.path,
-            nar_size: self.nar_size as u64,
-            nar_hash: NixHash::from_str(&self.nar_hash).unwrap(),
-            file_size: self.file_size.map(|x| x as u64),
-            file_hash: self.file_hash.map(|x| NixHash::from_str(&x).unwrap()),
-            url: self.url,
-            compression: self.compression.map(|x| Compression::from_str(&x).unwrap()),
-            deriver: self.deriver,
-            ca: self.ca,
-            signatures: self
+            path: db_path.path,
+            nar_size: db_path.nar_size as u64,
+            nar_hash: NixHash::from_str(&db_path.nar_hash).unwrap(),
+            file_size: db_path.file_size.map(|x| x as u64),
+            file_hash: db_path.file_hash.map(|x| NixHash::from_str(&x).unwrap()),
+            url: db_path.url,
+            compression: db_path.compression.map(|x| Compression::from_str(&x).unwrap()),
+            deriver: db_path.deriver,
+            ca: db_path.ca,
+            signatures: db_path
                 .sigs
-                .split(" ")
+                .split(' ')
+                .filter(|x| !x.is_empty())
                 .map(|x| {
-                    let sig = Signature::from_str(&x).unwrap();
+                    let sig = Signature::from_str(x).unwrap();
                     (sig.key_name, sig.signature)
                 })
                 .collect(),
-            references: self.refs.split(" ").map(|x| x.to_string()).collect(),
+            references: references.into_iter().collect(),
         }
     }
 }
+
+/// The current time as stored in the database, in seconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+impl DbPath {
+    /// The number of bytes the path takes up in the backend.
+    pub fn stored_size(&self) -> i64 {
+        self.file_size.unwrap_or(self.nar_size)
+    }
+
+    /// How the stored NAR is compressed, which is bzip2 unless stated otherwise.
+    pub fn compression(&self) -> Result<Compression, Error> {
+        match self.compression.as_deref() {
+            Some(compression) => Compression::from_str(compression).map_err(|_| Error::BadNarInfo),
+            None => Ok(Compression::Bzip2),
+        }
+    }
+
+    /// The key of the path's NAR in the backend.
+    pub fn backend_url(&self) -> Option<&str> {
+        self.url.as_deref().and_then(backend_url)
+    }
+}
+
+/// The key of the NAR at a narinfo `url` in the backend, which is the url without `nar/`.
+pub fn backend_url(url: &str) -> Option<&str> {
+    url.strip_prefix("nar/")
+}
+
+/// Adds the signatures in `new` that are not in `existing` yet, both separated by spaces
+/// like in the `sigs` column.
+pub fn merge_sigs(existing: &str, new: &str) -> String {
+    let mut sigs = existing.split(' ').filter(|sig| !sig.is_empty()).collect::<Vec<_>>();
+    for sig in new.split(' ').filter(|sig| !sig.is_empty()) {
+        if !sigs.contains(&sig) {
+            sigs.push(sig);
+        }
+    }
+    sigs.join(" ")
+}
+
+/// Describes how `new` differs from the cached `path` in the fields that signatures
+/// cover, apart from the store path itself.
+pub fn fingerprint_mismatch(
+    cached: &DbPath,
+    cached_refs: &BTreeSet<String>,
+    new: &DbPath,
+    new_refs: &BTreeSet<String>,
+) -> Option<String> {
+    let mut fields = Vec::new();
+    if cached.nar_hash != new.nar_hash {
+        fields.push("NarHash");
+    }
+    if cached.nar_size != new.nar_size {
+        fields.push("NarSize");
+    }
+    if cached_refs != new_refs {
+        fields.push("References");
+    }
+    match fields.is_empty() {
+        true => None,
+        false => Some(format!("the cached path has a different {}", fields.join(", "))),
+    }
+}
+
+/// Queries on `paths` that keep the `refs` and `objects` tables consistent with it.
+pub trait PathQueries {
+    /// Loads a path along with the store paths it references.
+    fn load_path(&self, id: &str) -> QueryResult<Option<(DbPath, Vec<String>)>>;
+    /// Inserts a new path, which starts out at the current inflation value.
+    fn insert_path(&self, path: &DbPath, references: &BTreeSet<String>) -> QueryResult<()>;
+    /// Adds up batched accesses in a single transaction, skipping paths deleted since. The
+    /// paths are aged to the current inflation value.
+    fn record_accesses(&self, accesses: &HashMap<String, Access>) -> QueryResult<()>;
+    /// Inserts a path, or merges the signatures of `path` into it if it exists already
+    /// with the same fingerprint. Returns whether the path was new.
+    ///
+    /// With `shared`, the path reuses a stored object, which fails with
+    /// [`Error::ObjectGone`] if the object was deleted since it was looked up.
+    fn upsert_path(&self, path: &DbPath, references: &BTreeSet<String>, shared: bool) -> Result<bool, Error>;
+    /// Deletes a path along with its pin. Returns the `url` of its object if no other path
+    /// uses it any more, which the caller then has to remove from the backend.
+    fn delete_path(&self, id: &str) -> QueryResult<Option<String>>;
+    /// The `url` of a stored object with the given `file_hash`.
+    fn find_object(&self, file_hash: &str) -> QueryResult<Option<String>>;
+    fn has_object(&self, url: &str) -> QueryResult<bool>;
+    /// All cached paths in the closure of a path, including the path itself.
+    fn closure(&self, id: &str) -> QueryResult<Vec<DbPath>>;
+    /// Store paths in the closure of a path which are not in the cache.
+    fn missing_references(&self, id: &str) -> QueryResult<Vec<String>>;
+    /// Cached paths directly referencing a path.
+    fn referrers(&self, id: &str) -> QueryResult<Vec<DbPath>>;
+    /// The ids among `ids` that are not in the cache, in a single query.
+    fn missing_paths(&self, ids: &[String]) -> QueryResult<Vec<String>>;
+    /// Paths whose store path contains `text`, ordered by store path.
+    fn search_paths(&self, text: &str, limit: i64) -> QueryResult<Vec<DbPath>>;
+    fn usage(&self) -> QueryResult<Usage>;
+}
+
+// SQLite's LIKE ignores case and Postgres' does not, so both sides are lowered.
+sql_function!(fn lower(x: Text) -> Text);
+
+/// Computes the closure of the ids returned by the `{seed}` query.
+pub const CLOSURE_CTE: &str = "
+    WITH RECURSIVE closure(id) AS (
+        {seed}
+        UNION
+        SELECT paths.id FROM closure
+        JOIN refs ON refs.referrer = closure.id
+        JOIN paths ON paths.path = refs.reference
+    )";
+
+impl_for_connections!(PathQueries {
+    fn load_path(&self, id: &str) -> QueryResult<Option<(DbPath, Vec<String>)>> {
+        let db_path = match all_paths.find(id).first::<DbPath>(self).optional()? {
+            Some(db_path) => db_path,
+            None => return Ok(None),
+        };
+        let references = all_refs
+            .select(refs::reference)
+            .filter(refs::referrer.eq(id))
+            .load::<String>(self)?;
+        Ok(Some((db_path, references)))
+    }
+
+    fn record_accesses(&self, accesses: &HashMap<String, Access>) -> QueryResult<()> {
+        self.transaction(|| {
+            let inflation = self.inflation()?;
+            for (id, access) in accesses {
+                diesel::update(all_paths.find(id))
+                    .set((
+                        paths::last_accessed.eq(access.last_accessed),
+                        paths::hit_count.eq(paths::hit_count + access.hits),
+                        paths::inflation.eq(inflation),
+                    ))
+                    .execute(self)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn insert_path(&self, path: &DbPath, references: &BTreeSet<String>) -> QueryResult<()> {
+        let db_refs = references
+            .iter()
+            .map(|reference| DbRef {
+                referrer: path.id.clone(),
+                reference: reference.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.transaction(|| {
+            let path = DbPath { inflation: self.inflation()?, ..path.clone() };
+            diesel::insert_into(all_paths).values(&path).execute(self)?;
+            diesel::insert_into(all_refs).values(&db_refs).execute(self)?;
+            if let Some(url) = &path.url {
+                let updated = diesel::update(all_objects.find(url))
+                    .set(objects::refcount.eq(objects::refcount + 1))
+                    .execute(self)?;
+                if updated == 0 {
+                    let object = DbObject {
+                        url: url.clone(),
+                        file_hash: path.file_hash.clone(),
+                        refcount: 1,
+                    };
+                    diesel::insert_into(all_objects).values(&object).execute(self)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn upsert_path(&self, path: &DbPath, references: &BTreeSet<String>, shared: bool) -> Result<bool, Error> {
+        self.transaction(|| match self.load_path(&path.id)? {
+            Some((cached, cached_refs)) => {
+                // Signatures for different contents must not end up on the cached ones.
+                let cached_refs = cached_refs.into_iter().collect::<BTreeSet<_>>();
+                if let Some(mismatch) = fingerprint_mismatch(&cached, &cached_refs, path, references) {
+                    return Err(Error::InvalidUpload(mismatch));
+                }
+                diesel::update(all_paths.find(&path.id))
+                    .set(paths::sigs.eq(merge_sigs(&cached.sigs, &path.sigs)))
+                    .execute(self)?;
+                Ok(false)
+            }
+            None => {
+                if let (true, Some(url)) = (shared, &path.url) {
+                    // Locks the object until the path is inserted, so that it cannot be
+                    // deleted in between.
+                    let locked = diesel::update(all_objects.find(url))
+                        .set(objects::refcount.eq(objects::refcount))
+                        .execute(self)?;
+                    if locked == 0 {
+                        return Err(Error::ObjectGone);
+                    }
+                }
+                self.insert_path(path, references)?;
+                Ok(true)
+            }
+        })
+    }
+
+    fn delete_path(&self, id: &str) -> QueryResult<Option<String>> {
+        self.transaction(|| {
+            let url = all_paths.find(id).select(paths::url).first::<Option<String>>(self).optional()?;
+            diesel::delete(all_refs.filter(refs::referrer.eq(id))).execute(self)?;
+            diesel::delete(pins::table.find(id)).execute(self)?;
+            diesel::delete(all_paths.find(id)).execute(self)?;
+            let url = match url.flatten() {
+                Some(url) => url,
+                None => return Ok(None),
+            };
+            diesel::update(all_objects.find(&url))
+                .set(objects::refcount.eq(objects::refcount - 1))
+                .execute(self)?;
+            let unused = diesel::delete(all_objects.find(&url).filter(objects::refcount.le(0))).execute(self)?;
+            Ok(if unused > 0 { Some(url) } else { None })
+        })
+    }
+
+    fn find_object(&self, file_hash: &str) -> QueryResult<Option<String>> {
+        all_objects
+            .select(objects::url)
+            .filter(objects::file_hash.eq(file_hash))
+            .first(self)
+            .optional()
+    }
+
+    fn has_object(&self, url: &str) -> QueryResult<bool> {
+        Ok(all_objects.find(url).select(objects::url).first::<String>(self).optional()?.is_some())
+    }
+
+    fn closure(&self, id: &str) -> QueryResult<Vec<DbPath>> {
+        let query = format!(
+            "{} SELECT paths.* FROM paths JOIN closure ON paths.id = closure.id",
+            CLOSURE_CTE.replace("{seed}", &format!("SELECT {}", Self::bind_param(1))),
+        );
+        diesel::sql_query(query).bind::<Text, _>(id).load(self)
+    }
+
+    fn missing_references(&self, id: &str) -> QueryResult<Vec<String>> {
+        let query = format!(
+            "{} SELECT DISTINCT refs.reference FROM closure
+            JOIN refs ON refs.referrer = closure.id
+            LEFT JOIN paths ON paths.path = refs.reference
+            WHERE paths.id IS NULL",
+            CLOSURE_CTE.replace("{seed}", &format!("SELECT {}", Self::bind_param(1))),
+        );
+        let rows = diesel::sql_query(query).bind::<Text, _>(id).load::<ReferenceRow>(self)?;
+        Ok(rows.into_iter().map(|row| row.reference).collect())
+    }
+
+    fn referrers(&self, id: &str) -> QueryResult<Vec<DbPath>> {
+        let path = match all_paths.find(id).select(paths::path).first::<String>(self).optional()? {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+        let referrer_ids = all_refs
+            .select(refs::referrer)
+            .filter(refs::reference.eq(path));
+        all_paths.filter(paths::id.eq_any(referrer_ids)).load(self)
+    }
+
+    fn missing_paths(&self, ids: &[String]) -> QueryResult<Vec<String>> {
+        let present = all_paths
+            .select(paths::id)
+            .filter(paths::id.eq_any(ids))
+            .load::<String>(self)?
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        Ok(ids.iter().filter(|id| !present.contains(*id)).cloned().collect())
+    }
+
+    fn search_paths(&self, text: &str, limit: i64) -> QueryResult<Vec<DbPath>> {
+        let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        all_paths
+            .filter(lower(paths::path).like(lower(pattern)).escape('\\'))
+            .order(paths::path)
+            .limit(limit)
+            .load(self)
+    }
+
+    fn usage(&self) -> QueryResult<Usage> {
+        diesel::sql_query(
+            "SELECT COUNT(*) AS paths,
+                CAST(COALESCE(SUM(nar_size), 0) AS BIGINT) AS nar_bytes,
+                CAST(COALESCE(SUM(file_size), 0) AS BIGINT) AS file_bytes
+            FROM paths",
+        )
+        .get_result(self)
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn nar_info(nar_size: u64) -> NarInfo {
+        NarInfo::from_str(&format!(
+            "StorePath: /nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-cuda\n\
+             NarHash: sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s\n\
+             NarSize: {}\n\
+             FileSize: 6442450944\n\
+             References: 7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-cuda\n\
+             Sig: cache.example.org-1:AAAA\n",
+            nar_size
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn large_sizes_roundtrip() {
+        let db_path = DbPath::try_from(nar_info(8589934592)).unwrap();
+        assert_eq!(db_path.nar_size, 8589934592);
+        let nar_info: NarInfo = (db_path, vec![]).into();
+        assert_eq!(nar_info.nar_size, 8589934592);
+        assert_eq!(nar_info.file_size, Some(6442450944));
+    }
+
+    #[test]
+    fn oversized_rejected() {
+        assert_matches!(DbPath::try_from(nar_info(u64::MAX)), Err(Error::SizeOutOfRange));
+    }
+
+    #[test]
+    fn sigs_merge() {
+        assert_eq!(merge_sigs("a:1 b:2", "b:2 c:3"), "a:1 b:2 c:3");
+        assert_eq!(merge_sigs("", "a:1"), "a:1");
+        assert_eq!(merge_sigs("a:1", ""), "a:1");
+    }
+}
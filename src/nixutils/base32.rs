@@ -37,7 +37,7 @@ pub fn decoded_len(input_len: usize) -> usize {
     input_len * 5 / 8
 }
 
-static BASE32_CHARS: &[u8; 32] = &b"0123456789abcdfghijklmnpqrsvwxyz";
+static BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
 
 lazy_static! {
     static ref BASE32_CHARS_REVERSE: Box<[u8; 256]> = {
@@ -113,7 +113,6 @@ pub fn decode(input: &str) -> Result<Vec<u8>, crate::Error> {
 mod tests {
     use super::*;
     use assert_matches::assert_matches;
-    use hex;
     use proptest::proptest;
 
     #[test]
@@ -0,0 +1,173 @@
+//! The format of `nix-store --export` and `nix-store --import`.
+//!
+//! An export is a sequence of paths, each announced by the integer 1 and ended by the
+//! integer 0 after the last one. A path is its NAR followed by a trailer:
+//!
+//! ```text
+//! path    = 1 nar EXPORT_MAGIC store-path references deriver signature
+//! references = count { store-path }
+//! signature  = 0 | 1 string
+//! ```
+//!
+//! Integers are little endian u64, and strings are encoded like in NARs. An empty deriver
+//! means there is none, and the signature is a legacy one that nothing checks anymore.
+
+use crate::error::{Error, Result};
+use std::collections::BTreeSet;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const EXPORT_MAGIC: u64 = 0x4558_494e;
+
+/// Longest store path accepted in a trailer.
+const MAX_PATH: u64 = 4096;
+
+/// Most references accepted for one path.
+const MAX_REFERENCES: u64 = 100_000;
+
+/// The metadata following the NAR of an exported path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportTrailer {
+    pub path: String,
+    pub references: BTreeSet<String>,
+    /// The store path of the deriver.
+    pub deriver: Option<String>,
+}
+
+/// Whether another path follows, rather than the end of the export.
+pub async fn read_next<R: AsyncRead + Unpin>(reader: &mut R) -> Result<bool> {
+    match reader.read_u64_le().await? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(Error::BadExport("expected the start of a path or the end")),
+    }
+}
+
+/// Reads the trailer after a NAR.
+pub async fn read_trailer<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ExportTrailer> {
+    if reader.read_u64_le().await? != EXPORT_MAGIC {
+        return Err(Error::BadExport("missing magic after NAR"));
+    }
+    let path = read_store_path(reader).await?;
+    let count = reader.read_u64_le().await?;
+    if count > MAX_REFERENCES {
+        return Err(Error::BadExport("too many references"));
+    }
+    let mut references = BTreeSet::new();
+    for _ in 0..count {
+        references.insert(read_store_path(reader).await?);
+    }
+    let deriver = match read_string(reader).await? {
+        deriver if deriver.is_empty() => None,
+        deriver => Some(check_store_path(deriver)?),
+    };
+    if reader.read_u64_le().await? != 0 {
+        read_string(reader).await?;
+    }
+    Ok(ExportTrailer { path, references, deriver })
+}
+
+/// Announces the next path, or the end of the export.
+pub async fn write_next<W: AsyncWrite + Unpin>(writer: &mut W, next: bool) -> Result<()> {
+    writer.write_u64_le(next as u64).await?;
+    Ok(())
+}
+
+/// Writes the trailer after a NAR.
+pub async fn write_trailer<W: AsyncWrite + Unpin>(writer: &mut W, trailer: &ExportTrailer) -> Result<()> {
+    writer.write_u64_le(EXPORT_MAGIC).await?;
+    write_string(writer, &trailer.path).await?;
+    writer.write_u64_le(trailer.references.len() as u64).await?;
+    for reference in &trailer.references {
+        write_string(writer, reference).await?;
+    }
+    write_string(writer, trailer.deriver.as_deref().unwrap_or_default()).await?;
+    writer.write_u64_le(0).await?;
+    Ok(())
+}
+
+async fn write_string<W: AsyncWrite + Unpin>(writer: &mut W, string: &str) -> Result<()> {
+    writer.write_u64_le(string.len() as u64).await?;
+    writer.write_all(string.as_bytes()).await?;
+    writer.write_all(&[0; 8][..(8 - string.len() % 8) % 8]).await?;
+    Ok(())
+}
+
+async fn read_store_path<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    check_store_path(read_string(reader).await?)
+}
+
+fn check_store_path(path: String) -> Result<String> {
+    match path.strip_prefix("/nix/store/") {
+        Some(name) if !name.is_empty() && !name.contains('/') => Ok(path),
+        _ => Err(Error::BadExport("invalid store path")),
+    }
+}
+
+async fn read_string<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let len = reader.read_u64_le().await?;
+    if len > MAX_PATH {
+        return Err(Error::BadExport("string too long"));
+    }
+    let mut string = vec![0; len as usize + (8 - len as usize % 8) % 8];
+    reader.read_exact(&mut string).await?;
+    if string[len as usize..].iter().any(|&b| b != 0) {
+        return Err(Error::BadExport("non-zero padding"));
+    }
+    string.truncate(len as usize);
+    String::from_utf8(string).map_err(|_| Error::BadExport("string is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = (s.len() as u64).to_le_bytes().to_vec();
+        out.extend_from_slice(s.as_bytes());
+        out.resize(out.len() + (8 - s.len() % 8) % 8, 0);
+        out
+    }
+
+    fn trailer(path: &str, references: &[&str], deriver: &str) -> Vec<u8> {
+        let mut out = EXPORT_MAGIC.to_le_bytes().to_vec();
+        out.extend(string(path));
+        out.extend((references.len() as u64).to_le_bytes());
+        for reference in references {
+            out.extend(string(reference));
+        }
+        out.extend(string(deriver));
+        out.extend(0u64.to_le_bytes());
+        out
+    }
+
+    #[rocket::async_test]
+    async fn trailers() {
+        let hello = "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello";
+        let glibc = "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn1-glibc";
+        let drv = "/nix/store/0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv";
+        let mut bytes = trailer(hello, &[glibc, hello], drv);
+        bytes.extend(trailer(glibc, &[], ""));
+        let mut reader = bytes.as_slice();
+        let first = read_trailer(&mut reader).await.unwrap();
+        assert_eq!(first, ExportTrailer {
+            path: hello.to_string(),
+            references: [hello.to_string(), glibc.to_string()].into_iter().collect(),
+            deriver: Some(drv.to_string()),
+        });
+        let second = read_trailer(&mut reader).await.unwrap();
+        assert_eq!(second.deriver, None);
+        assert!(reader.is_empty());
+
+        let mut written = Vec::new();
+        write_trailer(&mut written, &first).await.unwrap();
+        write_trailer(&mut written, &second).await.unwrap();
+        // References are written sorted.
+        assert_eq!(written[..written.len() - trailer(glibc, &[], "").len()], trailer(hello, &[hello, glibc], drv));
+        assert_eq!(written[written.len() - trailer(glibc, &[], "").len()..], trailer(glibc, &[], ""));
+
+        assert_matches!(read_trailer(&mut &trailer("/tmp/hello", &[], "")[..]).await, Err(Error::BadExport(_)));
+        assert_matches!(read_trailer(&mut &trailer(hello, &["/nix/store/a/b"], "")[..]).await, Err(Error::BadExport(_)));
+        assert_matches!(read_trailer(&mut &bytes[8..]).await, Err(Error::BadExport(_)));
+    }
+}
@@ -0,0 +1,75 @@
+use super::{HashType, NixHash};
+use crate::error::Error;
+use ring::digest;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+pub struct Hasher {
+    hash_type: HashType,
+    context: digest::Context,
+}
+
+impl Hasher {
+    pub fn new(hash_type: HashType) -> Result<Self, Error> {
+        let algorithm = match hash_type {
+            HashType::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            HashType::Sha256 => &digest::SHA256,
+            HashType::Sha512 => &digest::SHA512,
+            HashType::Md5 => return Err(Error::UnknownHashType),
+        };
+        Ok(Hasher {
+            hash_type,
+            context: digest::Context::new(algorithm),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.context.update(data);
+    }
+
+    pub fn finish(self) -> NixHash {
+        NixHash {
+            hash_type: self.hash_type,
+            hash: self.context.finish().as_ref().to_vec(),
+        }
+    }
+}
+
+/// Passes reads through to the inner reader while hashing and counting the bytes.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, hash_type: HashType) -> Result<Self, Error> {
+        Ok(HashingReader {
+            inner,
+            hasher: Hasher::new(hash_type)?,
+            size: 0,
+        })
+    }
+
+    pub fn finish(self) -> (NixHash, u64) {
+        (self.hasher.finish(), self.size)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let new = &buf.filled()[before..];
+            self.hasher.update(new);
+            self.size += new.len() as u64;
+        }
+        poll
+    }
+}
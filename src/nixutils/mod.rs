@@ -1,11 +1,22 @@
 mod base32;
+mod hashing;
+pub mod export;
+pub mod nar;
+pub mod store_db;
+
+pub use hashing::{Hasher, HashingReader};
 
 use crate::error::Error;
+use async_compression::tokio::bufread::{
+    BzDecoder, BzEncoder, GzipDecoder, GzipEncoder, XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder,
+};
 use log::warn;
 use ring::signature;
+use serde::Deserialize;
 use std::collections::{BTreeSet, HashMap};
 use std::str::FromStr;
 use strum_macros::{AsRefStr, EnumString};
+use tokio::io::{AsyncRead, BufReader};
 
 #[derive(Debug, Clone)]
 pub struct Signature {
@@ -66,24 +77,70 @@ pub enum HashType {
     Sha512,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct NixHash {
     hash_type: HashType,
     hash: Vec<u8>,
 }
 
+impl NixHash {
+    pub fn hash_type(&self) -> &HashType {
+        &self.hash_type
+    }
+}
+
+impl HashType {
+    /// Length of a digest in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            HashType::Md5 => 16,
+            HashType::Sha1 => 20,
+            HashType::Sha256 => 32,
+            HashType::Sha512 => 64,
+        }
+    }
+}
+
+/// Parses `type:hash` with the hash in base16, Nix's base32 or base64, telling them
+/// apart by length like Nix does, and SRI hashes of the form `type-base64`.
 impl FromStr for NixHash {
     type Err = Error;
     fn from_str(s: &str) -> std::result::Result<NixHash, Self::Err> {
-        let mut parts = s.splitn(2, ":");
-        Ok(NixHash {
-            hash_type: HashType::from_str(parts.next().ok_or(Error::UnexpectedEof)?)
-                .map_err(|_| Error::UnknownHashType)?,
-            hash: base32::decode(parts.next().ok_or(Error::UnexpectedEof)?)?,
-        })
+        let (hash_type, hash, sri) = match s.split_once(':') {
+            Some((hash_type, hash)) => (hash_type, hash, false),
+            None => {
+                let (hash_type, hash) = s.split_once('-').ok_or(Error::UnexpectedEof)?;
+                (hash_type, hash, true)
+            }
+        };
+        let hash_type = HashType::from_str(hash_type).map_err(|_| Error::UnknownHashType)?;
+        let size = hash_type.size();
+        let hash = if sri {
+            base64::decode(hash)?
+        } else if hash.len() == 2 * size {
+            decode_base16(hash)?
+        } else if hash.len() == base32::encoded_len(size) {
+            base32::decode(hash)?
+        } else {
+            base64::decode(hash)?
+        };
+        if hash.len() != size {
+            return Err(Error::BadHash);
+        }
+        Ok(NixHash { hash_type, hash })
     }
 }
 
+fn decode_base16(s: &str) -> Result<Vec<u8>, Error> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::BadHash);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| Error::BadHash))
+        .collect()
+}
+
 impl std::fmt::Display for NixHash {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -95,8 +152,10 @@ impl std::fmt::Display for NixHash {
     }
 }
 
-#[derive(AsRefStr, EnumString, PartialEq, Debug, Clone)]
+#[derive(AsRefStr, EnumString, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
 pub enum Compression {
+    #[default]
     #[strum(serialize = "xz")]
     Xz,
     #[strum(serialize = "bzip2")]
@@ -106,9 +165,63 @@ pub enum Compression {
     #[strum(serialize = "zstd")]
     Zstd,
     #[strum(serialize = "none")]
+    #[serde(rename = "none")]
     Plain,
 }
 
+
+impl Compression {
+    /// The suffix of NAR file names in this compression, as used by Nix.
+    pub fn nar_extension(&self) -> &'static str {
+        match self {
+            Compression::Xz => ".nar.xz",
+            Compression::Bzip2 => ".nar.bz2",
+            Compression::Gzip => ".nar.gz",
+            Compression::Zstd => ".nar.zst",
+            Compression::Plain => ".nar",
+        }
+    }
+
+    /// The compression of a NAR file named with one of the suffixes in
+    /// [`Self::nar_extension`].
+    pub fn from_nar_name(name: &str) -> Option<Compression> {
+        let (_, extension) = name.split_once('.')?;
+        [Compression::Xz, Compression::Bzip2, Compression::Gzip, Compression::Zstd, Compression::Plain]
+            .into_iter()
+            .find(|compression| compression.nar_extension()[1..] == *extension)
+    }
+
+    /// Wraps `reader` so that reading from it yields the decompressed data.
+    pub fn decoder<'a, R>(&self, reader: R) -> Box<dyn AsyncRead + Send + Unpin + 'a>
+    where
+        R: AsyncRead + Send + Unpin + 'a,
+    {
+        let reader = BufReader::new(reader);
+        match self {
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+            Compression::Gzip => Box::new(GzipDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(reader)),
+            Compression::Plain => Box::new(reader),
+        }
+    }
+
+    /// Wraps `reader` so that reading from it yields the compressed data.
+    pub fn encoder<'a, R>(&self, reader: R) -> Box<dyn AsyncRead + Send + Unpin + 'a>
+    where
+        R: AsyncRead + Send + Unpin + 'a,
+    {
+        let reader = BufReader::new(reader);
+        match self {
+            Compression::Xz => Box::new(XzEncoder::new(reader)),
+            Compression::Bzip2 => Box::new(BzEncoder::new(reader)),
+            Compression::Gzip => Box::new(GzipEncoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdEncoder::new(reader)),
+            Compression::Plain => Box::new(reader),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NarInfo {
     pub path: String,
@@ -150,12 +263,12 @@ impl NarInfo {
                     &signature::ED25519,
                     trusted_key.pub_key.clone(),
                 );
-                if let Ok(()) = peer_public_key.verify(fingerprint.as_bytes(), &sig) {
+                if let Ok(()) = peer_public_key.verify(fingerprint.as_bytes(), sig) {
                     return Ok(SignatureVerified);
                 }
             }
         }
-        return Err(Error::NoValidSignature);
+        Err(Error::NoValidSignature)
     }
 }
 
@@ -197,7 +310,7 @@ impl FromStr for NarInfo {
                 }
                 "Deriver" => deriver = Some(value.into()),
                 "References" => {
-                    for r in value.split(' ') {
+                    for r in value.split(' ').filter(|r| !r.is_empty()) {
                         references.insert(format!("/nix/store/{}", r));
                     }
                 }
@@ -230,25 +343,25 @@ impl FromStr for NarInfo {
 
 impl std::fmt::Display for NarInfo {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(fmt, "StorePath: {}\n", self.path)?;
-        write!(fmt, "NarHash: {}\n", self.nar_hash)?;
-        write!(fmt, "NarSize: {}\n", self.nar_size)?;
+        writeln!(fmt, "StorePath: {}", self.path)?;
+        writeln!(fmt, "NarHash: {}", self.nar_hash)?;
+        writeln!(fmt, "NarSize: {}", self.nar_size)?;
         if let Some(file_hash) = self.file_hash.as_ref() {
-            write!(fmt, "FileHash: {}\n", file_hash)?;
+            writeln!(fmt, "FileHash: {}", file_hash)?;
         }
         if let Some(file_size) = self.file_size {
-            write!(fmt, "FileSize: {}\n", file_size)?;
+            writeln!(fmt, "FileSize: {}", file_size)?;
         }
         if let Some(url) = self.url.as_ref() {
-            write!(fmt, "URL: {}\n", url)?;
+            writeln!(fmt, "URL: {}", url)?;
         }
         if let Some(compression) = self.compression.as_ref() {
-            write!(fmt, "Compression: {}\n", (*compression).as_ref())?;
+            writeln!(fmt, "Compression: {}", (*compression).as_ref())?;
         }
         if let Some(deriver) = self.deriver.as_ref() {
-            write!(fmt, "Deriver: {}\n", deriver)?;
+            writeln!(fmt, "Deriver: {}", deriver)?;
         }
-        if self.references.len() > 0 {
+        if !self.references.is_empty() {
             write!(fmt, "References:")?;
             for reference in &self.references {
                 if let Some(stripped) = reference.strip_prefix("/nix/store/") {
@@ -257,14 +370,35 @@ impl std::fmt::Display for NarInfo {
                     warn!("invalid store prefix in saved narinfo");
                 }
             }
-            write!(fmt, "\n")?;
+            writeln!(fmt)?;
         }
         for sig in self.signatures.clone() {
-            write!(fmt, "Sig: {}\n", Signature { key_name: sig.0, signature: sig.1 })?;
+            writeln!(fmt, "Sig: {}", Signature { key_name: sig.0, signature: sig.1 })?;
         }
         if let Some(ca) = self.ca.as_ref() {
-            write!(fmt, "CA: {}\n", ca)?;
+            writeln!(fmt, "CA: {}", ca)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_formats() {
+        let base32 = "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s";
+        let expected = NixHash::from_str(base32).unwrap();
+        for s in [
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "sha256:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+            "sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+        ] {
+            assert_eq!(NixHash::from_str(s).unwrap(), expected, "{}", s);
+        }
+        assert_eq!(expected.to_string(), base32);
+        assert!(NixHash::from_str("sha256:ba7816bf").is_err());
+        assert!(NixHash::from_str("sha1-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=").is_err());
+    }
+}
@@ -0,0 +1,586 @@
+//! Streaming reader and writer for the NAR archive format.
+//!
+//! A NAR serializes a file system object as a sequence of strings, each prefixed by its
+//! length as a little endian u64 and padded with zeros to a multiple of 8 bytes:
+//!
+//! ```text
+//! nar       = "nix-archive-1" node
+//! node      = "(" "type" ( regular | symlink | directory ) ")"
+//! regular   = "regular" [ "executable" "" ] "contents" contents
+//! symlink   = "symlink" "target" target
+//! directory = "directory" { "entry" "(" "name" name "node" node ")" }
+//! ```
+//!
+//! Directory entries are sorted by name, so every file system object has exactly one NAR.
+
+use crate::error::{Error, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const MAGIC: &[u8] = b"nix-archive-1";
+
+/// Longest string other than file contents that is accepted, which bounds the memory a
+/// malicious archive can make us allocate.
+const MAX_STRING: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NarNode {
+    Directory,
+    Symlink { target: String },
+    /// `nar_offset` is where the contents start in the archive.
+    File { executable: bool, size: u64, nar_offset: u64 },
+}
+
+/// A file system object in an archive. `path` is relative to the root of the archive,
+/// which itself has the empty path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NarEntry {
+    pub path: String,
+    pub node: NarNode,
+}
+
+/// Joins a directory path and an entry name.
+pub fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+fn padding(len: u64) -> usize {
+    ((8 - len % 8) % 8) as usize
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(Error::BadNar("invalid entry name"));
+    }
+    Ok(())
+}
+
+struct OpenDir {
+    path: String,
+    last: Option<String>,
+}
+
+/// Parses a NAR one entry at a time, in archive order.
+///
+/// After [`NarReader::next`] returned a file, the reader itself reads the contents of
+/// that file. Contents that are not read are skipped by the next call.
+pub struct NarReader<R> {
+    reader: R,
+    /// Bytes of the archive read so far.
+    position: u64,
+    started: bool,
+    dirs: Vec<OpenDir>,
+    /// Unread contents of the current file, followed by its padding.
+    remaining: u64,
+    padding: usize,
+    in_file: bool,
+}
+
+impl<R: AsyncRead + Unpin> NarReader<R> {
+    pub fn new(reader: R) -> Self {
+        NarReader {
+            reader,
+            position: 0,
+            started: false,
+            dirs: Vec::new(),
+            remaining: 0,
+            padding: 0,
+            in_file: false,
+        }
+    }
+
+    /// The next entry, or `None` at the end of the archive.
+    pub async fn next(&mut self) -> Result<Option<NarEntry>> {
+        if !self.started {
+            self.started = true;
+            self.expect(MAGIC).await?;
+            return self.read_node(String::new()).await.map(Some);
+        }
+        if self.in_file {
+            self.in_file = false;
+            let remaining = self.remaining;
+            if tokio::io::copy(&mut (&mut self.reader).take(remaining), &mut tokio::io::sink()).await? != remaining {
+                return Err(Error::UnexpectedEof);
+            }
+            self.position += remaining;
+            self.read_padding(self.padding).await?;
+            self.expect(b")").await?;
+            self.close_entry().await?;
+        }
+        loop {
+            let dir = match self.dirs.last() {
+                Some(dir) => dir,
+                None => return Ok(None),
+            };
+            let dir_path = dir.path.clone();
+            match self.read_string().await?.as_slice() {
+                b"entry" => {
+                    self.expect(b"(").await?;
+                    self.expect(b"name").await?;
+                    let name = String::from_utf8(self.read_string().await?)
+                        .map_err(|_| Error::BadNar("entry name is not UTF-8"))?;
+                    check_name(&name)?;
+                    let dir = self.dirs.last_mut().expect("checked above");
+                    if dir.last.as_ref().is_some_and(|last| *last >= name) {
+                        return Err(Error::BadNar("directory entries not sorted"));
+                    }
+                    dir.last = Some(name.clone());
+                    self.expect(b"node").await?;
+                    return self.read_node(join(&dir_path, &name)).await.map(Some);
+                }
+                b")" => {
+                    self.dirs.pop();
+                    self.close_entry().await?;
+                }
+                _ => return Err(Error::BadNar("expected entry or end of directory")),
+            }
+        }
+    }
+
+    /// Fails unless the archive is followed by the end of input, and returns the reader.
+    pub async fn finish(self) -> Result<R> {
+        let mut reader = self.into_inner().await?;
+        let mut byte = [0];
+        if reader.read(&mut byte).await? != 0 {
+            return Err(Error::BadNar("trailing data after archive"));
+        }
+        Ok(reader)
+    }
+
+    /// Reads the rest of the archive and returns the reader, positioned right after it.
+    pub async fn into_inner(mut self) -> Result<R> {
+        while self.next().await?.is_some() {}
+        Ok(self.reader)
+    }
+
+    async fn read_node(&mut self, path: String) -> Result<NarEntry> {
+        self.expect(b"(").await?;
+        self.expect(b"type").await?;
+        let node = match self.read_string().await?.as_slice() {
+            b"regular" => {
+                let mut tag = self.read_string().await?;
+                let executable = tag == b"executable";
+                if executable {
+                    self.expect(b"").await?;
+                    tag = self.read_string().await?;
+                }
+                if tag != b"contents" {
+                    return Err(Error::BadNar("expected contents"));
+                }
+                let size = self.reader.read_u64_le().await?;
+                self.position += 8;
+                self.remaining = size;
+                self.padding = padding(size);
+                self.in_file = true;
+                NarNode::File { executable, size, nar_offset: self.position }
+            }
+            b"symlink" => {
+                self.expect(b"target").await?;
+                let target = String::from_utf8(self.read_string().await?)
+                    .map_err(|_| Error::BadNar("symlink target is not UTF-8"))?;
+                if target.is_empty() || target.contains('\0') {
+                    return Err(Error::BadNar("invalid symlink target"));
+                }
+                self.expect(b")").await?;
+                self.close_entry().await?;
+                NarNode::Symlink { target }
+            }
+            b"directory" => {
+                self.dirs.push(OpenDir { path: path.clone(), last: None });
+                NarNode::Directory
+            }
+            _ => return Err(Error::BadNar("unknown node type")),
+        };
+        Ok(NarEntry { path, node })
+    }
+
+    /// Reads the `)` closing a directory entry once its node is complete.
+    async fn close_entry(&mut self) -> Result<()> {
+        if !self.dirs.is_empty() {
+            self.expect(b")").await?;
+        }
+        Ok(())
+    }
+
+    async fn read_string(&mut self) -> Result<Vec<u8>> {
+        let len = self.reader.read_u64_le().await?;
+        if len > MAX_STRING {
+            return Err(Error::BadNar("string too long"));
+        }
+        let mut string = vec![0; len as usize];
+        self.reader.read_exact(&mut string).await?;
+        self.position += 8 + len;
+        self.read_padding(padding(len)).await?;
+        Ok(string)
+    }
+
+    async fn read_padding(&mut self, len: usize) -> Result<()> {
+        let mut padding = [0; 8];
+        self.reader.read_exact(&mut padding[..len]).await?;
+        self.position += len as u64;
+        if padding.iter().any(|&b| b != 0) {
+            return Err(Error::BadNar("non-zero padding"));
+        }
+        Ok(())
+    }
+
+    async fn expect(&mut self, token: &[u8]) -> Result<()> {
+        if self.read_string().await? != token {
+            return Err(Error::BadNar("unexpected token"));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the contents of the file returned last by [`NarReader::next`].
+impl<R: AsyncRead + Unpin> AsyncRead for NarReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if !self.in_file || self.remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let limit = buf.remaining().min(self.remaining.min(usize::MAX as u64) as usize);
+        let mut limited = buf.take(limit);
+        let poll = Pin::new(&mut self.reader).poll_read(cx, &mut limited);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = limited.filled().len();
+            if read == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            // SAFETY: `limited` only filled bytes inside of `buf`'s unfilled part.
+            unsafe { buf.assume_init(read) };
+            buf.advance(read);
+            self.remaining -= read as u64;
+            self.position += read as u64;
+        }
+        poll
+    }
+}
+
+/// Serializes entries, which have to be given in archive order, into a NAR.
+pub struct NarWriter<W> {
+    writer: W,
+    started: bool,
+    /// Directories that may still get entries, from the root down.
+    dirs: Vec<OpenDir>,
+}
+
+impl<W: AsyncWrite + Unpin> NarWriter<W> {
+    pub fn new(writer: W) -> Self {
+        NarWriter {
+            writer,
+            started: false,
+            dirs: Vec::new(),
+        }
+    }
+
+    pub async fn directory(&mut self, path: &str) -> Result<()> {
+        self.begin_node(path, b"directory").await?;
+        self.dirs.push(OpenDir { path: path.to_string(), last: None });
+        Ok(())
+    }
+
+    pub async fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        if target.is_empty() || target.contains('\0') {
+            return Err(Error::BadNar("invalid symlink target"));
+        }
+        self.begin_node(path, b"symlink").await?;
+        self.write_string(b"target").await?;
+        self.write_string(target.as_bytes()).await?;
+        self.end_node(path).await
+    }
+
+    /// Writes a file with exactly `size` bytes read from `contents`.
+    pub async fn file<C: AsyncRead + Unpin>(&mut self, path: &str, executable: bool, size: u64, contents: C) -> Result<()> {
+        self.begin_node(path, b"regular").await?;
+        if executable {
+            self.write_string(b"executable").await?;
+            self.write_string(b"").await?;
+        }
+        self.write_string(b"contents").await?;
+        self.writer.write_u64_le(size).await?;
+        let copied = tokio::io::copy(&mut contents.take(size), &mut self.writer).await?;
+        if copied != size {
+            return Err(Error::UnexpectedEof);
+        }
+        self.writer.write_all(&[0; 8][..padding(size)]).await?;
+        self.end_node(path).await
+    }
+
+    /// Closes the open directories and returns the writer.
+    pub async fn finish(mut self) -> Result<W> {
+        if !self.started {
+            return Err(Error::BadNar("empty archive"));
+        }
+        self.close_dirs_until(None).await?;
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+
+    async fn begin_node(&mut self, path: &str, node_type: &[u8]) -> Result<()> {
+        if !self.started {
+            if !path.is_empty() {
+                return Err(Error::BadNar("archive has to start at the root"));
+            }
+            self.started = true;
+            self.write_string(MAGIC).await?;
+        } else {
+            let (parent, name) = match path.rsplit_once('/') {
+                Some((parent, name)) => (parent, name),
+                None if !path.is_empty() => ("", path),
+                None => return Err(Error::BadNar("root written twice")),
+            };
+            check_name(name)?;
+            if !self.dirs.iter().any(|dir| dir.path == parent) {
+                return Err(Error::BadNar("entry outside of any open directory"));
+            }
+            self.close_dirs_until(Some(parent)).await?;
+            let dir = self.dirs.last_mut().ok_or(Error::BadNar("entry outside of any directory"))?;
+            if dir.last.as_deref().is_some_and(|last| last >= name) {
+                return Err(Error::BadNar("directory entries not sorted"));
+            }
+            dir.last = Some(name.to_string());
+            self.write_string(b"entry").await?;
+            self.write_string(b"(").await?;
+            self.write_string(b"name").await?;
+            self.write_string(name.as_bytes()).await?;
+            self.write_string(b"node").await?;
+        }
+        self.write_string(b"(").await?;
+        self.write_string(b"type").await?;
+        self.write_string(node_type).await
+    }
+
+    async fn end_node(&mut self, path: &str) -> Result<()> {
+        self.write_string(b")").await?;
+        if !path.is_empty() {
+            self.write_string(b")").await?;
+        }
+        Ok(())
+    }
+
+    /// Closes open directories until `path` is the innermost one, or all of them.
+    async fn close_dirs_until(&mut self, path: Option<&str>) -> Result<()> {
+        while let Some(dir) = self.dirs.last() {
+            if Some(dir.path.as_str()) == path {
+                return Ok(());
+            }
+            let dir = self.dirs.pop().expect("checked above");
+            self.end_node(&dir.path).await?;
+        }
+        match path {
+            Some(_) => Err(Error::BadNar("entry outside of any directory")),
+            None => Ok(()),
+        }
+    }
+
+    async fn write_string(&mut self, string: &[u8]) -> Result<()> {
+        self.writer.write_u64_le(string.len() as u64).await?;
+        self.writer.write_all(string).await?;
+        self.writer.write_all(&[0; 8][..padding(string.len() as u64)]).await?;
+        Ok(())
+    }
+}
+
+/// Serializes the file system object at `path` into a NAR, without following symlinks.
+pub async fn dump<W: AsyncWrite + Unpin>(path: &Path, writer: W) -> Result<W> {
+    let mut writer = NarWriter::new(writer);
+    // Objects still to be written, the next one last.
+    let mut pending = vec![(path.to_path_buf(), String::new())];
+    while let Some((fs_path, nar_path)) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&fs_path).await?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            writer.directory(&nar_path).await?;
+            let mut names = Vec::new();
+            let mut entries = tokio::fs::read_dir(&fs_path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                names.push(entry.file_name().into_string().map_err(|_| Error::BadNar("entry name is not UTF-8"))?);
+            }
+            names.sort();
+            pending.extend(names.into_iter().rev().map(|name| (fs_path.join(&name), join(&nar_path, &name))));
+        } else if file_type.is_symlink() {
+            let target = tokio::fs::read_link(&fs_path).await?;
+            let target = target.to_str().ok_or(Error::BadNar("symlink target is not UTF-8"))?;
+            writer.symlink(&nar_path, target).await?;
+        } else if file_type.is_file() {
+            let executable = metadata.permissions().mode() & 0o100 != 0;
+            let file = tokio::fs::File::open(&fs_path).await?;
+            writer.file(&nar_path, executable, metadata.len(), file).await?;
+        } else {
+            return Err(Error::BadNar("unsupported file type"));
+        }
+    }
+    writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut out = (s.len() as u64).to_le_bytes().to_vec();
+        out.extend_from_slice(s);
+        out.resize(out.len() + padding(s.len() as u64), 0);
+        out
+    }
+
+    fn strings(tokens: &[&[u8]]) -> Vec<u8> {
+        tokens.iter().flat_map(|token| string(token)).collect()
+    }
+
+    /// A directory with an executable, a symlink and a subdirectory with a file, built
+    /// by hand from the format description.
+    fn sample() -> Vec<u8> {
+        strings(&[
+            b"nix-archive-1", b"(", b"type", b"directory",
+            b"entry", b"(", b"name", b"bin", b"node",
+                b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"hello", b"node",
+                    b"(", b"type", b"regular", b"executable", b"", b"contents", b"#!/bin/sh\n", b")",
+                b")",
+                b")",
+            b")",
+            b"entry", b"(", b"name", b"lib", b"node",
+                b"(", b"type", b"symlink", b"target", b"bin", b")",
+            b")",
+            b"entry", b"(", b"name", b"share", b"node",
+                b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"README", b"node",
+                    b"(", b"type", b"regular", b"contents", b"hi", b")",
+                b")",
+                b")",
+            b")",
+            b")",
+        ])
+    }
+
+    async fn read_all(nar: &[u8]) -> Result<Vec<(NarEntry, Vec<u8>)>> {
+        let mut reader = NarReader::new(nar);
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next().await? {
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).await?;
+            entries.push((entry, contents));
+        }
+        reader.finish().await?;
+        Ok(entries)
+    }
+
+    fn entry(path: &str, node: NarNode) -> NarEntry {
+        NarEntry { path: path.to_string(), node }
+    }
+
+    #[rocket::async_test]
+    async fn read_sample() {
+        let nar = sample();
+        let entries = read_all(&nar).await.unwrap();
+        let file = |executable, contents: &[u8]| {
+            let encoded = string(contents);
+            let start = nar.windows(encoded.len()).position(|window| window == encoded).unwrap();
+            NarNode::File { executable, size: contents.len() as u64, nar_offset: start as u64 + 8 }
+        };
+        assert_eq!(
+            entries,
+            vec![
+                (entry("", NarNode::Directory), vec![]),
+                (entry("bin", NarNode::Directory), vec![]),
+                (entry("bin/hello", file(true, b"#!/bin/sh\n")), b"#!/bin/sh\n".to_vec()),
+                (entry("lib", NarNode::Symlink { target: "bin".to_string() }), vec![]),
+                (entry("share", NarNode::Directory), vec![]),
+                (entry("share/README", file(false, b"hi")), b"hi".to_vec()),
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn skip_contents() {
+        let nar = sample();
+        let mut reader = NarReader::new(&nar[..]);
+        let mut paths = Vec::new();
+        while let Some(entry) = reader.next().await.unwrap() {
+            paths.push(entry.path);
+        }
+        assert_eq!(paths, vec!["", "bin", "bin/hello", "lib", "share", "share/README"]);
+    }
+
+    #[rocket::async_test]
+    async fn write_sample() {
+        let mut writer = NarWriter::new(Vec::new());
+        writer.directory("").await.unwrap();
+        writer.directory("bin").await.unwrap();
+        writer.file("bin/hello", true, 10, &b"#!/bin/sh\n"[..]).await.unwrap();
+        writer.symlink("lib", "bin").await.unwrap();
+        writer.directory("share").await.unwrap();
+        writer.file("share/README", false, 2, &b"hi"[..]).await.unwrap();
+        assert_eq!(writer.finish().await.unwrap(), sample());
+    }
+
+    #[rocket::async_test]
+    async fn single_file() {
+        let nar = strings(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"hello world", b")"]);
+        let mut writer = NarWriter::new(Vec::new());
+        writer.file("", false, 11, &b"hello world"[..]).await.unwrap();
+        assert_eq!(writer.finish().await.unwrap(), nar);
+        let entries = read_all(&nar).await.unwrap();
+        assert_eq!(entries, vec![(entry("", NarNode::File { executable: false, size: 11, nar_offset: 96 }), b"hello world".to_vec())]);
+    }
+
+    #[rocket::async_test]
+    async fn reject_malformed() {
+        let dir = |names: &[&[u8]]| {
+            let mut tokens: Vec<&[u8]> = vec![b"nix-archive-1", b"(", b"type", b"directory"];
+            for name in names {
+                tokens.extend_from_slice(&[b"entry", b"(", b"name", name, b"node"]);
+                tokens.extend_from_slice(&[b"(", b"type", b"symlink", b"target", b"x", b")", b")"]);
+            }
+            tokens.push(b")");
+            strings(&tokens)
+        };
+        assert!(read_all(&dir(&[b"a", b"b"])).await.is_ok());
+        assert_matches!(read_all(&dir(&[b"b", b"a"])).await, Err(Error::BadNar(_)));
+        assert_matches!(read_all(&dir(&[b"a", b"a"])).await, Err(Error::BadNar(_)));
+        assert_matches!(read_all(&dir(&[b".."])).await, Err(Error::BadNar(_)));
+        assert_matches!(read_all(&dir(&[b"a/b"])).await, Err(Error::BadNar(_)));
+
+        let mut bad_padding = strings(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"x", b")"]);
+        let contents = strings(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents"]).len();
+        bad_padding[contents + 8 + 1] = 1;
+        assert_matches!(read_all(&bad_padding).await, Err(Error::BadNar(_)));
+
+        let nar = sample();
+        assert!(read_all(&nar[..nar.len() - 8]).await.is_err());
+        let mut trailing = nar.clone();
+        trailing.push(0);
+        assert_matches!(read_all(&trailing).await, Err(Error::BadNar(_)));
+    }
+
+    #[rocket::async_test]
+    async fn dump_sample() {
+        let root = std::env::temp_dir().join(format!("nyancache-dump-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::create_dir_all(root.join("share")).unwrap();
+        std::fs::write(root.join("bin/hello"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(root.join("bin/hello"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("bin", root.join("lib")).unwrap();
+        std::fs::write(root.join("share/README"), "hi").unwrap();
+        let nar = dump(&root, Vec::new()).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(nar.unwrap(), sample());
+    }
+
+    #[rocket::async_test]
+    async fn writer_checks_order() {
+        let mut writer = NarWriter::new(Vec::new());
+        writer.directory("").await.unwrap();
+        writer.symlink("b", "x").await.unwrap();
+        assert_matches!(writer.symlink("a", "x").await, Err(Error::BadNar(_)));
+        assert_matches!(writer.symlink("c/d", "x").await, Err(Error::BadNar(_)));
+    }
+}
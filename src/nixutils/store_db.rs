@@ -0,0 +1,190 @@
+//! Read-only access to the database in which Nix records the valid paths of a store,
+//! usually `/nix/var/nix/db/db.sqlite`.
+
+use super::{NarInfo, NixHash, Signature};
+use crate::error::{Error, Result};
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use diesel::{sql_query, Connection, RunQueryDsl, SqliteConnection};
+use diesel_derives::QueryableByName;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(Debug, QueryableByName)]
+struct ValidPath {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "Text"]
+    path: String,
+    /// `sha256:` and the NAR hash in base16.
+    #[sql_type = "Text"]
+    hash: String,
+    #[sql_type = "Nullable<Text>"]
+    deriver: Option<String>,
+    #[sql_type = "Nullable<BigInt>"]
+    #[column_name = "narSize"]
+    nar_size: Option<i64>,
+    /// Space separated.
+    #[sql_type = "Nullable<Text>"]
+    sigs: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    ca: Option<String>,
+}
+
+#[derive(Debug, QueryableByName)]
+struct Reference {
+    #[sql_type = "Text"]
+    path: String,
+}
+
+pub struct StoreDb {
+    conn: Mutex<SqliteConnection>,
+    /// The store directory that paths in the database start with.
+    store_prefix: String,
+}
+
+impl StoreDb {
+    /// Opens an existing database, which Nix may keep writing to.
+    pub fn open(path: &Path, store_prefix: &str) -> Result<Self> {
+        if !path.is_file() {
+            return Err(Error::NotFound);
+        }
+        let conn = SqliteConnection::establish(path.to_str().ok_or(Error::Backend)?).map_err(|_| Error::Backend)?;
+        // Nix holds write locks for the duration of its transactions.
+        conn.batch_execute("PRAGMA query_only = ON; PRAGMA busy_timeout = 10000;")?;
+        Ok(StoreDb {
+            conn: Mutex::new(conn),
+            store_prefix: store_prefix.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Describes the valid path with the given hash part, without url and compression.
+    pub fn query_path(&self, id: &str) -> Result<Option<NarInfo>> {
+        let conn = self.conn.lock().expect("not poisoned");
+        // Store paths sort by their hash part, so this is the first path at or after it.
+        let prefix = format!("{}/{}-", self.store_prefix, id);
+        let row = sql_query("SELECT id, path, hash, deriver, narSize, sigs, ca FROM ValidPaths WHERE path >= ? ORDER BY path LIMIT 1")
+            .bind::<Text, _>(&prefix)
+            .load::<ValidPath>(&*conn)?
+            .into_iter()
+            .next();
+        let row = match row {
+            Some(row) if row.path.starts_with(&prefix) => row,
+            _ => return Ok(None),
+        };
+        let references = sql_query("SELECT ValidPaths.path FROM Refs JOIN ValidPaths ON ValidPaths.id = Refs.reference WHERE Refs.referrer = ?")
+            .bind::<Integer, _>(row.id)
+            .load::<Reference>(&*conn)?
+            .into_iter()
+            .map(|reference| self.served(reference.path))
+            .collect::<BTreeSet<_>>();
+
+        let mut signatures = HashMap::new();
+        for signature in row.sigs.iter().flat_map(|sigs| sigs.split(' ')).filter(|sig| !sig.is_empty()) {
+            let signature = Signature::from_str(signature)?;
+            signatures.insert(signature.key_name, signature.signature);
+        }
+        let deriver = row.deriver.map(|deriver| match deriver.strip_prefix(&format!("{}/", self.store_prefix)) {
+            Some(name) => name.to_string(),
+            None => deriver,
+        });
+        Ok(Some(NarInfo {
+            path: self.served(row.path),
+            nar_hash: NixHash::from_str(&row.hash)?,
+            // Only databases from before Nix 1.0 lack the size.
+            nar_size: row.nar_size.and_then(|size| u64::try_from(size).ok()).ok_or(Error::SizeOutOfRange)?,
+            file_hash: None,
+            file_size: None,
+            url: None,
+            compression: None,
+            deriver,
+            ca: row.ca,
+            references,
+            signatures,
+        }))
+    }
+
+    /// A path from the database as the cache serves it, under `/nix/store` like every
+    /// path in its narinfos.
+    fn served(&self, path: String) -> String {
+        match path.strip_prefix(&format!("{}/", self.store_prefix)) {
+            Some(name) => format!("/nix/store/{}", name),
+            None => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tables of Nix's schema 10 that are read, with two paths.
+    const FIXTURE: &str = r#"
+        CREATE TABLE ValidPaths (
+            id integer primary key autoincrement not null,
+            path text unique not null,
+            hash text not null,
+            registrationTime integer not null,
+            deriver text,
+            narSize integer,
+            ultimate integer,
+            sigs text,
+            ca text
+        );
+        CREATE TABLE Refs (
+            referrer integer not null,
+            reference integer not null,
+            primary key (referrer, reference)
+        );
+        INSERT INTO ValidPaths VALUES
+            (1, '/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello', 'sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad',
+             1700000000, '/nix/store/0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv', 456, NULL,
+             'cache.example.org-1:eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eHh4eA==', NULL),
+            (2, '/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn1-glibc', 'sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad',
+             1700000000, NULL, 1024, NULL, NULL, 'fixed:out:sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s');
+        INSERT INTO Refs VALUES (1, 1), (1, 2);
+    "#;
+
+    #[test]
+    fn query_paths() {
+        let path = std::env::temp_dir().join(format!("nyancache-store-db-{}.sqlite", std::process::id()));
+        SqliteConnection::establish(path.to_str().unwrap()).unwrap().batch_execute(FIXTURE).unwrap();
+        let db = StoreDb::open(&path, "/nix/store/").unwrap();
+        let hello = db.query_path("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0").unwrap().unwrap();
+        let glibc = db.query_path("7m7bchi96yfplyh3cbpmpj6rk4nlcjn1").unwrap().unwrap();
+        let missing = db.query_path("7m7bchi96yfplyh3cbpmpj6rk4nlcjn").unwrap();
+        let read_only = db.conn.lock().unwrap().batch_execute("DELETE FROM Refs");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(hello.path, "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello");
+        assert_eq!(hello.nar_hash.to_string(), "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s");
+        assert_eq!(hello.nar_size, 456);
+        assert_eq!(hello.deriver.as_deref(), Some("0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv"));
+        assert_eq!(hello.references.into_iter().collect::<Vec<_>>(), vec![
+            "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello",
+            "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn1-glibc",
+        ]);
+        assert!(hello.signatures.contains_key("cache.example.org-1"));
+        assert!(glibc.references.is_empty());
+        assert!(glibc.ca.is_some());
+        assert!(missing.is_none());
+        assert!(read_only.is_err());
+    }
+
+    #[test]
+    fn foreign_prefix() {
+        let path = std::env::temp_dir().join(format!("nyancache-store-db-prefix-{}.sqlite", std::process::id()));
+        let fixture = FIXTURE.replace("/nix/store/", "/tmp/root/store/");
+        SqliteConnection::establish(path.to_str().unwrap()).unwrap().batch_execute(&fixture).unwrap();
+        let db = StoreDb::open(&path, "/tmp/root/store").unwrap();
+        let hello = db.query_path("7m7bchi96yfplyh3cbpmpj6rk4nlcjn0").unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(hello.path, "/nix/store/7m7bchi96yfplyh3cbpmpj6rk4nlcjn0-hello");
+        assert_eq!(hello.deriver.as_deref(), Some("0bk4mb5k8ljgvqh0yk9k5rhiv4xqjylr-hello.drv"));
+        assert!(hello.references.iter().all(|reference| reference.starts_with("/nix/store/")));
+        assert_eq!(hello.references.len(), 2);
+    }
+}
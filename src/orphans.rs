@@ -0,0 +1,118 @@
+use crate::backend::{Area, Backend, ObjectInfo};
+use crate::chunks::{chunk_key, ChunkQueries};
+use crate::db::{db_run, DbConn};
+use crate::error::Result;
+use crate::listing::listing_key;
+use crate::schema::paths::dsl::paths;
+use crate::schema::paths::id as db_id;
+use crate::schema::paths::url as db_url;
+
+use diesel::{QueryDsl, RunQueryDsl};
+use log::info;
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, clap::Args)]
+pub struct OrphanOptions {
+    /// Only consider objects last modified longer ago than this
+    #[clap(long, default_value = "1day", parse(try_from_str = humantime::parse_duration))]
+    pub grace_period: Duration,
+    /// Delete the orphans instead of only reporting them
+    #[clap(long)]
+    pub delete: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct OrphanReport {
+    /// Finished objects that no path refers to.
+    pub data: Vec<ObjectInfo>,
+    /// Uploads that were never completed.
+    pub tmp: Vec<ObjectInfo>,
+}
+
+/// Finds backend objects without a row in `paths` that are older than the grace period.
+pub async fn find_orphans(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    options: &OrphanOptions,
+) -> Result<OrphanReport> {
+    let cutoff = SystemTime::now() - options.grace_period;
+    let is_old = |object: &ObjectInfo| object.last_modified < cutoff;
+
+    // List the backend before the database, so that an upload completing in between
+    // is never mistaken for an orphan.
+    let data = backend.list(Area::Data).await?;
+    let tmp = backend.list(Area::Tmp).await?;
+
+    let mut referenced: BTreeSet<String> = db_run!(conn, |c| paths.select(db_url).load::<Option<String>>(c))?
+        .into_iter()
+        .flatten()
+        .filter_map(|url| url.strip_prefix("nar/").map(|x| x.to_string()))
+        .collect();
+    referenced.extend(db_run!(conn, |c| c.chunk_hashes())?.iter().map(|hash| chunk_key(hash)));
+    referenced.extend(db_run!(conn, |c| paths.select(db_id).load::<String>(c))?.iter().map(|id| listing_key(id)));
+
+    let report = OrphanReport {
+        data: data
+            .into_iter()
+            .filter(|object| is_old(object) && !referenced.contains(&object.url))
+            .collect(),
+        tmp: tmp.into_iter().filter(is_old).collect(),
+    };
+
+    if options.delete {
+        for object in &report.data {
+            info!("deleting orphaned object {}", object.url);
+            backend.delete_nar(&object.url).await?;
+        }
+        for object in &report.tmp {
+            info!("deleting abandoned upload {}", object.url);
+            backend.abort_nar(&object.url).await?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{client, file_nar, test_root, upload};
+    use crate::State;
+
+    use std::sync::Arc;
+
+    async fn urls(backend: &(dyn Backend + Send + Sync), area: Area) -> BTreeSet<String> {
+        backend.list(area).await.unwrap().into_iter().map(|object| object.url).collect()
+    }
+
+    #[rocket::async_test]
+    async fn orphans() {
+        let client = client("orphans", true).await;
+        let conn = DbConn::get_one(client.rocket()).await.unwrap();
+        let backend = &*client.rocket().state::<Arc<State>>().unwrap().backend;
+        let id = "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0";
+        let contents = (0..4096).map(|n| (n * 7919 % 251) as u8).collect::<Vec<_>>();
+        upload(&client, &format!("/nix/store/{}-kept", id), &file_nar(&contents).await, &[]).await;
+        let kept = urls(backend, Area::Data).await;
+        let orphaned_chunk = chunk_key(&"0".repeat(64));
+        for url in ["orphaned.nar.xz", orphaned_chunk.as_str(), "abandoned.nar.xz"] {
+            backend.write_nar(url, &mut &b"orphan"[..]).await.unwrap();
+        }
+        backend.finish_nar("orphaned.nar.xz").await.unwrap();
+        backend.finish_nar(&orphaned_chunk).await.unwrap();
+        let mut options = OrphanOptions { grace_period: Duration::ZERO, delete: false };
+
+        let report = find_orphans(&conn, backend, &options).await.unwrap();
+        let data = report.data.iter().map(|object| object.url.as_str()).collect::<BTreeSet<_>>();
+        assert_eq!(data, BTreeSet::from([orphaned_chunk.as_str(), "orphaned.nar.xz"]));
+        assert_eq!(report.tmp.iter().map(|object| object.url.as_str()).collect::<Vec<_>>(), vec!["abandoned.nar.xz"]);
+        assert!(kept.contains(&listing_key(id)));
+        assert!(kept.iter().any(|url| url.starts_with("chunks/")));
+
+        options.delete = true;
+        find_orphans(&conn, backend, &options).await.unwrap();
+        assert_eq!(urls(backend, Area::Data).await, kept);
+        assert!(urls(backend, Area::Tmp).await.is_empty());
+        std::fs::remove_dir_all(test_root("orphans")).unwrap();
+    }
+}
@@ -4,15 +4,71 @@ table! {
         path -> Text,
         registration_time -> Nullable<BigInt>,
         last_accessed -> Nullable<BigInt>,
-        nar_size -> Integer,
+        nar_size -> BigInt,
         nar_hash -> Text,
-        file_size -> Nullable<Integer>,
+        file_size -> Nullable<BigInt>,
         file_hash -> Nullable<Text>,
         url -> Nullable<Text>,
         compression -> Nullable<Text>,
         deriver -> Nullable<Text>,
         ca -> Nullable<Text>,
         sigs -> Text,
-        refs -> Text,
+        hit_count -> BigInt,
+        inflation -> Double,
     }
 }
+
+table! {
+    refs (referrer, reference) {
+        referrer -> Text,
+        reference -> Text,
+    }
+}
+
+table! {
+    pins (id) {
+        id -> Text,
+        created -> BigInt,
+        expires -> Nullable<BigInt>,
+        note -> Nullable<Text>,
+    }
+}
+
+table! {
+    gc_runs (id) {
+        id -> BigInt,
+        started -> BigInt,
+        finished -> BigInt,
+        total_bytes -> BigInt,
+        pinned_bytes -> BigInt,
+        evicted_paths -> BigInt,
+        evicted_bytes -> BigInt,
+        inflation -> Double,
+    }
+}
+
+table! {
+    objects (url) {
+        url -> Text,
+        file_hash -> Nullable<Text>,
+        refcount -> BigInt,
+    }
+}
+
+table! {
+    chunks (hash) {
+        hash -> Text,
+        size -> BigInt,
+        refcount -> BigInt,
+    }
+}
+
+table! {
+    object_chunks (url, seq) {
+        url -> Text,
+        seq -> BigInt,
+        hash -> Text,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(paths, refs, pins, objects, chunks, object_chunks);
@@ -0,0 +1,401 @@
+use crate::backend::Backend;
+use crate::chunks::{self, ChunkQueries};
+use crate::db::{db_run, DbConn};
+use crate::error::{Error, Result};
+use crate::listing;
+use crate::models::{DbPath, PathQueries};
+use crate::nixutils::nar::NarReader;
+use crate::nixutils::{Compression, HashType, HashingReader, NixHash};
+use crate::schema::paths::dsl::paths;
+use crate::schema::paths::id as db_id;
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use log::{error, warn};
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+
+const PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum)]
+pub enum ScrubAction {
+    Report,
+    Quarantine,
+    Delete,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ScrubOptions {
+    /// Also decompress every NAR and check NarHash and NarSize
+    #[clap(long)]
+    pub check_nar: bool,
+    /// What to do with corrupt or missing entries
+    #[clap(long, arg_enum, default_value = "report")]
+    pub action: ScrubAction,
+    /// Also write the .ls listings of healthy paths that have none
+    #[clap(long)]
+    pub listings: bool,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The url of the path does not point into the backend, if it has one at all.
+    BadUrl(Option<String>),
+    Missing,
+    Unreadable(Error),
+    FileSize { expected: u64, actual: u64 },
+    FileHash { expected: NixHash, actual: NixHash },
+    /// The object was uploaded in another compression than the narinfo names.
+    Compression { expected: Compression, actual: Compression },
+    Undecodable(std::io::Error),
+    Malformed(Error),
+    NarSize { expected: u64, actual: u64 },
+    NarHash { expected: NixHash, actual: NixHash },
+}
+
+impl Problem {
+    /// Whether the problem proves the object is broken, as opposed to the backend being
+    /// temporarily unreachable or the row not pointing at an object to check.
+    fn is_definitive(&self) -> bool {
+        !matches!(self, Problem::Unreadable(_) | Problem::BadUrl(_))
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Problem::BadUrl(Some(url)) => write!(fmt, "URL {} is not in the backend", url),
+            Problem::BadUrl(None) => write!(fmt, "no URL"),
+            Problem::Missing => write!(fmt, "object missing from backend"),
+            Problem::Unreadable(e) => write!(fmt, "object could not be read: {}", e),
+            Problem::FileSize { expected, actual } => {
+                write!(fmt, "FileSize is {}, expected {}", actual, expected)
+            }
+            Problem::FileHash { expected, actual } => {
+                write!(fmt, "FileHash is {}, expected {}", actual, expected)
+            }
+            Problem::Compression { expected, actual } => {
+                write!(fmt, "Compression is {}, expected {}", actual.as_ref(), expected.as_ref())
+            }
+            Problem::Undecodable(e) => write!(fmt, "NAR could not be decompressed: {}", e),
+            Problem::Malformed(e) => write!(fmt, "NAR is malformed: {}", e),
+            Problem::NarSize { expected, actual } => {
+                write!(fmt, "NarSize is {}, expected {}", actual, expected)
+            }
+            Problem::NarHash { expected, actual } => {
+                write!(fmt, "NarHash is {}, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub checked: u64,
+    pub bad: Vec<(DbPath, Vec<Problem>)>,
+    /// Bad paths that the action failed on, which are left as they are.
+    pub failed: u64,
+}
+
+/// Walks all paths in the database and verifies their backend objects.
+pub async fn scrub(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    options: &ScrubOptions,
+) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+    let mut last_id = String::new();
+    loop {
+        let after = last_id.clone();
+        let page = db_run!(conn, |c| {
+            paths
+                .filter(db_id.gt(after))
+                .order(db_id)
+                .limit(PAGE_SIZE)
+                .load::<DbPath>(c)
+        })?;
+        let last = match page.last() {
+            Some(last) => last.id.clone(),
+            None => break,
+        };
+
+        for db_path in page {
+            report.checked += 1;
+            let problems = check_path(conn, backend, &db_path, options.check_nar).await;
+            if problems.is_empty() {
+                if options.listings {
+                    ensure_listing(conn, backend, &db_path).await;
+                }
+                continue;
+            }
+            for problem in &problems {
+                warn!("{}: {}", db_path.id, problem);
+            }
+            if problems.iter().all(Problem::is_definitive) {
+                if let Err(e) = act(conn, backend, &db_path, &problems, options.action).await {
+                    error!("{}: {:?} failed: {}", db_path.id, options.action, e);
+                    report.failed += 1;
+                }
+            }
+            report.bad.push((db_path, problems));
+        }
+        last_id = last;
+    }
+    Ok(report)
+}
+
+/// Lists paths that were cached before listings were generated on upload.
+async fn ensure_listing(conn: &DbConn, backend: &(dyn Backend + Send + Sync), db_path: &DbPath) {
+    match backend.read_nar(&listing::listing_key(&db_path.id)).await {
+        Err(Error::NotFound) => listing::try_store_listing(conn, backend, db_path).await,
+        Err(e) => warn!("{}: listing could not be read: {}", db_path.id, e),
+        Ok(_) => (),
+    }
+}
+
+async fn act(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    db_path: &DbPath,
+    problems: &[Problem],
+    action: ScrubAction,
+) -> Result<()> {
+    if action == ScrubAction::Report {
+        return Ok(());
+    }
+    let missing = problems.iter().any(|p| matches!(p, Problem::Missing));
+    let id = db_path.id.clone();
+    let object_url = db_path.url.clone().unwrap_or_default();
+    let (chunked, unused) = db_run!(conn, |c| {
+        Ok::<_, diesel::result::Error>((c.is_chunked(&object_url)?, c.delete_path(&id)?))
+    })?;
+    backend.delete_nar(&listing::listing_key(&db_path.id)).await?;
+    match (action, db_path.backend_url()) {
+        // A corrupt object is just as corrupt for the other paths sharing it. Chunks may
+        // be shared with healthy objects, so chunked objects are deleted instead.
+        (ScrubAction::Quarantine, Some(url)) if !missing && !chunked => backend.quarantine_nar(url).await?,
+        _ => {
+            if let Some(url) = unused {
+                chunks::delete_object(conn, backend, &url).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn check_path(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    db_path: &DbPath,
+    check_nar: bool,
+) -> Vec<Problem> {
+    match check_object(conn, backend, db_path, check_nar).await {
+        Ok(problems) => problems,
+        Err(Error::NotFound) => vec![Problem::Missing],
+        Err(e) => vec![Problem::Unreadable(e)],
+    }
+}
+
+/// The hash and size of a NAR, or what kept it from being read.
+type NarDigest = std::result::Result<(NixHash, u64), Problem>;
+
+/// What an object turned out to contain while it was written, to check it against its
+/// narinfo without reading it back from the backend.
+#[derive(Debug)]
+pub struct Measured {
+    compression: Compression,
+    file: (NixHash, u64),
+    nar: NarDigest,
+}
+
+impl Measured {
+    pub fn new(compression: Compression, file: (NixHash, u64), nar: (NixHash, u64)) -> Self {
+        Measured { compression, file, nar: Ok(nar) }
+    }
+
+    /// Hashes an object with SHA-256 as it is read, and the NAR inside of it.
+    pub async fn measure<R: AsyncRead + Send + Unpin>(object: R, compression: Compression) -> Result<Measured> {
+        let mut file_reader = HashingReader::new(object, HashType::Sha256)?;
+        let nar = hash_nar(compression.decoder(&mut file_reader), HashType::Sha256).await?;
+        tokio::io::copy(&mut file_reader, &mut tokio::io::sink()).await?;
+        Ok(Measured { compression, file: file_reader.finish(), nar })
+    }
+
+    /// Compares the measurements with a narinfo, unless it uses other hashes than they
+    /// were taken with.
+    pub fn problems(self, db_path: &DbPath) -> Result<Option<Vec<Problem>>> {
+        let sha256 = |hash: &NixHash| *hash.hash_type() == HashType::Sha256;
+        let file_hash = db_path.file_hash.as_deref().map(NixHash::from_str).transpose()?;
+        if !file_hash.as_ref().is_none_or(sha256) || !sha256(&NixHash::from_str(&db_path.nar_hash)?) {
+            return Ok(None);
+        }
+        let compression = db_path.compression()?;
+        if compression != self.compression {
+            return Ok(Some(vec![Problem::Compression { expected: compression, actual: self.compression }]));
+        }
+        compare(db_path, Some(self.file), Some(self.nar)).map(Some)
+    }
+}
+
+/// Compares the object of a path with its narinfo, and with `check_nar` also the NAR
+/// inside of it.
+pub async fn check_object(
+    conn: &DbConn,
+    backend: &(dyn Backend + Send + Sync),
+    db_path: &DbPath,
+    check_nar: bool,
+) -> Result<Vec<Problem>> {
+    let url = match db_path.backend_url() {
+        Some(url) => url,
+        None => return Ok(vec![Problem::BadUrl(db_path.url.clone())]),
+    };
+    let nar_hash_type = NixHash::from_str(&db_path.nar_hash)?.hash_type().clone();
+    let object_url = db_path.url.clone().unwrap_or_default();
+    let manifest = db_run!(conn, |c| c.manifest(&object_url))?;
+    if !manifest.is_empty() {
+        // Chunked objects no longer exist in compressed form, only the NAR can be checked.
+        let nar = match check_nar {
+            true => Some(hash_nar(chunks::reassemble(backend, manifest), nar_hash_type).await?),
+            false => None,
+        };
+        return compare(db_path, None, nar);
+    }
+
+    let file_hash_type = db_path
+        .file_hash
+        .as_deref()
+        .map(NixHash::from_str)
+        .transpose()?
+        .map_or(HashType::Sha256, |h| h.hash_type().clone());
+    let mut file_reader = HashingReader::new(backend.read_nar(url).await?.into_reader(), file_hash_type)?;
+    let nar = match check_nar {
+        true => Some(hash_nar(db_path.compression()?.decoder(&mut file_reader), nar_hash_type).await?),
+        false => None,
+    };
+    // Drain whatever the decompressor left unread so the file hash covers the whole object.
+    tokio::io::copy(&mut file_reader, &mut tokio::io::sink()).await?;
+    compare(db_path, Some(file_reader.finish()), nar)
+}
+
+/// Reads a NAR to its end, checking its structure.
+async fn hash_nar<R: AsyncRead + Unpin>(nar: R, hash_type: HashType) -> Result<NarDigest> {
+    let mut nar_reader = HashingReader::new(nar, hash_type)?;
+    let mut archive = NarReader::new(&mut nar_reader);
+    let parsed = async {
+        while archive.next().await?.is_some() {}
+        archive.finish().await.map(|_| ())
+    };
+    Ok(match parsed.await {
+        Ok(()) => Ok(nar_reader.finish()),
+        Err(Error::Io(e)) => Err(Problem::Undecodable(e)),
+        Err(e) => Err(Problem::Malformed(e)),
+    })
+}
+
+/// Compares the hashes and sizes of an object and its NAR, where known, with a narinfo.
+fn compare(db_path: &DbPath, file: Option<(NixHash, u64)>, nar: Option<NarDigest>) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    match nar {
+        Some(Ok((nar_hash, nar_size))) => {
+            let expected_nar_hash = NixHash::from_str(&db_path.nar_hash)?;
+            if nar_size != db_path.nar_size as u64 {
+                problems.push(Problem::NarSize { expected: db_path.nar_size as u64, actual: nar_size });
+            }
+            if nar_hash != expected_nar_hash {
+                problems.push(Problem::NarHash { expected: expected_nar_hash, actual: nar_hash });
+            }
+        }
+        Some(Err(problem)) => problems.push(problem),
+        None => (),
+    }
+    if let Some((file_hash, file_size)) = file {
+        if let Some(expected) = db_path.file_size {
+            if file_size != expected as u64 {
+                problems.push(Problem::FileSize { expected: expected as u64, actual: file_size });
+            }
+        }
+        if let Some(expected) = db_path.file_hash.as_deref().map(NixHash::from_str).transpose()? {
+            if file_hash != expected {
+                problems.push(Problem::FileHash { expected, actual: file_hash });
+            }
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{client, file_nar, test_root, upload};
+    use crate::State;
+
+    use assert_matches::assert_matches;
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    async fn run(conn: &DbConn, backend: &(dyn Backend + Send + Sync), action: ScrubAction) -> ScrubReport {
+        let options = ScrubOptions { check_nar: true, action, listings: false };
+        scrub(conn, backend, &options).await.unwrap()
+    }
+
+    fn bad_ids(report: &ScrubReport) -> Vec<&str> {
+        report.bad.iter().map(|(db_path, _)| db_path.id.as_str()).collect()
+    }
+
+    fn backend_url(url: &str) -> &str {
+        crate::models::backend_url(url).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn outcomes() {
+        let client = client("scrub", false).await;
+        let conn = DbConn::get_one(client.rocket()).await.unwrap();
+        let backend = &*client.rocket().state::<Arc<State>>().unwrap().backend;
+        let data_dir = test_root("scrub").join("data");
+        let [ok, corrupt, missing, elsewhere] = ["ok", "corrupt", "missing", "elsewhere"].map(|name| format!("{:0>32}", name));
+        upload(&client, &format!("/nix/store/{}-ok", ok), &file_nar(b"ok").await, &[]).await;
+        let url = upload(&client, &format!("/nix/store/{}-corrupt", corrupt), &file_nar(b"corrupt").await, &[]).await;
+        std::fs::write(data_dir.join(backend_url(&url)), b"garbage").unwrap();
+        let url = upload(&client, &format!("/nix/store/{}-missing", missing), &file_nar(b"missing").await, &[]).await;
+        std::fs::remove_file(data_dir.join(backend_url(&url))).unwrap();
+        let db_path = DbPath {
+            id: elsewhere.clone(),
+            path: format!("/nix/store/{}-elsewhere", elsewhere),
+            url: Some("https://cache.nixos.org/nar/elsewhere.nar.xz".to_string()),
+            ..Default::default()
+        };
+        db_run!(conn, |c| c.insert_path(&db_path, &BTreeSet::new())).unwrap();
+
+        let report = run(&conn, backend, ScrubAction::Report).await;
+        assert_eq!(report.checked, 4);
+        assert_eq!(bad_ids(&report), vec![&corrupt, &missing, &elsewhere]);
+        assert_matches!(report.bad[0].1[..], [Problem::Undecodable(_) | Problem::Malformed(_), ..]);
+        assert_matches!(report.bad[1].1[..], [Problem::Missing]);
+        assert_matches!(report.bad[2].1[..], [Problem::BadUrl(Some(_))]);
+
+        let report = run(&conn, backend, ScrubAction::Delete).await;
+        assert_eq!(report.failed, 0);
+        let report = run(&conn, backend, ScrubAction::Report).await;
+        assert_eq!(report.checked, 2);
+        assert_eq!(bad_ids(&report), vec![&elsewhere]);
+        std::fs::remove_dir_all(test_root("scrub")).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn listings() {
+        let client = client("scrub-listings", false).await;
+        let conn = DbConn::get_one(client.rocket()).await.unwrap();
+        let backend = &*client.rocket().state::<Arc<State>>().unwrap().backend;
+        let id = format!("{:0>32}", "listed");
+        upload(&client, &format!("/nix/store/{}-listed", id), &file_nar(b"listed").await, &[]).await;
+        let listing = test_root("scrub-listings").join("data").join(listing::listing_key(&id));
+        std::fs::remove_file(&listing).unwrap();
+
+        // Reporting leaves the backend as it is.
+        run(&conn, backend, ScrubAction::Report).await;
+        assert!(!listing.exists());
+        let options = ScrubOptions { check_nar: false, action: ScrubAction::Report, listings: true };
+        scrub(&conn, backend, &options).await.unwrap();
+        assert!(listing.exists());
+        std::fs::remove_dir_all(test_root("scrub-listings")).unwrap();
+    }
+}
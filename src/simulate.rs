@@ -0,0 +1,274 @@
+use crate::config::GcConfig;
+use crate::gc::{self, policy::PolicyKind};
+use crate::models::DbPath;
+
+use log::warn;
+use rocket::data::ByteUnit;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use strum_macros::{AsRefStr, EnumString};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Debug, clap::Args)]
+pub struct SimulateOptions {
+    /// Access trace recorded by the server with `access_log`
+    pub trace: PathBuf,
+    /// Cache budget to simulate, may be given several times [default: gc.max_size]
+    #[clap(long = "max-size", parse(try_from_str = parse_byte_unit))]
+    pub max_sizes: Vec<ByteUnit>,
+    /// Eviction policy to simulate, may be given several times [default: all]
+    #[clap(long = "policy", arg_enum)]
+    pub policies: Vec<PolicyKind>,
+}
+
+fn parse_byte_unit(s: &str) -> Result<ByteUnit, String> {
+    s.parse().map_err(|e| format!("{:?}", e))
+}
+
+#[derive(AsRefStr, EnumString, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum TraceKind {
+    Narinfo,
+    Nar,
+}
+
+/// One request for a path, as `<unix time> <narinfo|nar> <id> <stored size>`. Lookups of
+/// paths that were not in the cache are recorded as narinfo requests without a size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub time: i64,
+    pub kind: TraceKind,
+    pub id: String,
+    pub size: Option<i64>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {} {}", self.time, self.kind.as_ref(), self.id)?;
+        match self.size {
+            Some(size) => write!(fmt, " {}", size),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for TraceRecord {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, ()> {
+        let mut fields = line.split_whitespace();
+        let mut next = || fields.next().ok_or(());
+        let record = TraceRecord {
+            time: next()?.parse().map_err(|_| ())?,
+            kind: next()?.parse().map_err(|_| ())?,
+            id: next()?.to_string(),
+            size: fields.next().map(str::parse).transpose().map_err(|_| ())?,
+        };
+        match (record.kind, record.size, fields.next()) {
+            (_, _, Some(_)) | (TraceKind::Nar, None, _) => Err(()),
+            _ => Ok(record),
+        }
+    }
+}
+
+/// Appends the requests for paths to the access log, including those the cache missed.
+pub struct TraceWriter(Mutex<tokio::fs::File>);
+
+impl TraceWriter {
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(TraceWriter(Mutex::new(file)))
+    }
+
+    /// Records a request. Failures are only logged, so that they never fail the request.
+    pub async fn record(&self, kind: TraceKind, db_path: &DbPath) {
+        self.write(kind, &db_path.id, Some(db_path.stored_size())).await
+    }
+
+    /// Records a narinfo lookup for a path that is not in the cache.
+    pub async fn record_miss(&self, id: &str) {
+        self.write(TraceKind::Narinfo, id, None).await
+    }
+
+    async fn write(&self, kind: TraceKind, id: &str, size: Option<i64>) {
+        let record = TraceRecord {
+            time: crate::models::now(),
+            kind,
+            id: id.to_string(),
+            size,
+        };
+        let line = format!("{}\n", record);
+        if let Err(e) = self.0.lock().await.write_all(line.as_bytes()).await {
+            warn!("failed to write access log: {}", e);
+        }
+    }
+}
+
+pub fn read_trace(path: &Path) -> anyhow::Result<Vec<TraceRecord>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            line.parse()
+                .map_err(|_| anyhow::anyhow!("{}:{}: malformed trace record", path.display(), n + 1))
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct SimulationReport {
+    pub requests: u64,
+    pub hits: u64,
+    /// Bytes of NARs requested.
+    pub bytes: i64,
+    /// Bytes of NARs that were served from the cache.
+    pub byte_hits: i64,
+    pub evicted_paths: i64,
+}
+
+impl SimulationReport {
+    pub fn hit_ratio(&self) -> f64 {
+        self.hits as f64 / self.requests.max(1) as f64
+    }
+
+    pub fn byte_hit_ratio(&self) -> f64 {
+        self.byte_hits as f64 / self.bytes.max(1) as f64
+    }
+}
+
+/// Replays a trace against a cache that starts out empty and is collected with
+/// [`gc::plan`] every `config.interval` of trace time, like the server does.
+///
+/// A path that misses is assumed to be uploaded again right away. The server missed the
+/// lookups recorded without a size, which would have been followed by a download of the
+/// NAR, so they stand for that download as well. Their paths are admitted with the size
+/// recorded for them elsewhere in the trace, if there is one.
+pub fn simulate(records: &[TraceRecord], config: &GcConfig) -> SimulationReport {
+    let sizes = records
+        .iter()
+        .filter_map(|record| Some((record.id.as_str(), record.size?)))
+        .collect::<HashMap<_, _>>();
+    let mut report = SimulationReport::default();
+    let mut cache = HashMap::<String, DbPath>::new();
+    let mut inflation = 0.0;
+    let mut next_gc = records.first().map_or(0, |record| record.time);
+    for record in records {
+        if record.time >= next_gc {
+            let db_paths = cache.values().cloned().collect::<Vec<_>>();
+            let (stats, victims) = gc::plan(config, &db_paths, &HashSet::new(), &HashMap::new(), record.time, 0, inflation);
+            for victim in victims {
+                cache.remove(&victim.id);
+            }
+            report.evicted_paths += stats.evicted_paths;
+            inflation = stats.inflation;
+            next_gc = record.time + config.interval.as_secs() as i64;
+        }
+
+        report.requests += 1;
+        let size = match (record.size, sizes.get(record.id.as_str())) {
+            (Some(size), _) | (None, Some(&size)) => size,
+            // Never uploaded while the trace was recorded, so there is nothing to admit.
+            (None, None) => continue,
+        };
+        let download = record.kind == TraceKind::Nar || record.size.is_none();
+        if download {
+            report.bytes += size;
+        }
+        match cache.get_mut(&record.id) {
+            Some(db_path) => {
+                report.hits += 1;
+                db_path.last_accessed = Some(record.time);
+                db_path.inflation = inflation;
+                if download {
+                    report.byte_hits += size;
+                    db_path.hit_count += 1;
+                }
+            }
+            None => {
+                cache.insert(
+                    record.id.clone(),
+                    DbPath {
+                        id: record.id.clone(),
+                        registration_time: Some(record.time),
+                        nar_size: size,
+                        file_size: Some(size),
+                        inflation,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record(time: i64, id: &str, size: i64) -> TraceRecord {
+        TraceRecord { time, kind: TraceKind::Nar, id: id.to_string(), size: Some(size) }
+    }
+
+    fn miss(time: i64, id: &str) -> TraceRecord {
+        TraceRecord { time, kind: TraceKind::Narinfo, id: id.to_string(), size: None }
+    }
+
+    #[test]
+    fn trace_roundtrip() {
+        let record = record(1000, "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0", 4096);
+        assert_eq!(record.to_string().parse::<TraceRecord>(), Ok(record));
+        let miss = miss(1000, "7m7bchi96yfplyh3cbpmpj6rk4nlcjn0");
+        assert_eq!(miss.to_string(), "1000 narinfo 7m7bchi96yfplyh3cbpmpj6rk4nlcjn0");
+        assert_eq!(miss.to_string().parse::<TraceRecord>(), Ok(miss));
+        assert!("1000 nar x".parse::<TraceRecord>().is_err());
+        assert!("1000 nar x 1 2".parse::<TraceRecord>().is_err());
+        assert!("1000 ls x 1".parse::<TraceRecord>().is_err());
+    }
+
+    #[test]
+    fn misses_are_replayed() {
+        let config = GcConfig {
+            max_size: Some(ByteUnit::from(1000u64)),
+            interval: Duration::ZERO,
+            ..Default::default()
+        };
+        // The server missed "a" twice, then it was uploaded.
+        let records = vec![miss(0, "a"), miss(1, "a"), record(2, "a", 100), miss(3, "unknown")];
+        let report = simulate(&records, &config);
+        assert_eq!(report.requests, 4);
+        // The simulated cache admits "a" on the first miss and serves the other requests.
+        assert_eq!((report.hits, report.byte_hits, report.bytes), (2, 200, 300));
+        assert_eq!(report.hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn budget_limits_hits() {
+        // "small" is requested often, "big" twice but does not fit next to it.
+        let records = vec![
+            record(0, "big", 100),
+            record(1, "small", 10),
+            record(2, "small", 10),
+            record(3, "small", 10),
+            record(4, "big", 100),
+        ];
+        let config = |max_size: u64| GcConfig {
+            max_size: Some(ByteUnit::from(max_size)),
+            policy: PolicyKind::Lfu,
+            interval: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let report = simulate(&records, &config(1000));
+        assert_eq!((report.hits, report.byte_hits, report.bytes), (3, 120, 230));
+
+        let report = simulate(&records, &config(50));
+        assert_eq!((report.hits, report.byte_hits), (2, 20));
+        assert_eq!(report.evicted_paths, 1);
+    }
+}
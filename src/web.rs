@@ -0,0 +1,217 @@
+//! Read-only HTML pages for exploring the cache.
+
+// Rocket's route attribute re-exports a generated `uri!` macro for every handler, which
+// nothing outside of this module uses.
+#![allow(unused_imports)]
+
+use crate::browse::browse_url;
+use crate::chunks::ChunkQueries;
+use crate::db::{db_run, DbConn};
+use crate::error::{Error, Result};
+use crate::gc::GcQueries;
+use crate::html::{escape, format_size, format_time, page};
+use crate::models::{now, DbPath, PathQueries};
+
+use rocket::response::content::Html;
+use std::fmt::Write;
+
+/// Most paths shown for a search.
+const SEARCH_LIMIT: i64 = 100;
+
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![index, search, path]
+}
+
+fn search_form(text: &str) -> String {
+    format!(
+        "<form action=\"/search\" method=\"get\"><input type=\"search\" name=\"q\" value=\"{}\" \
+         placeholder=\"Store path name\" size=\"40\"> <button>Search</button></form>\n",
+        escape(text)
+    )
+}
+
+fn path_link(id: &str, store_path: &str) -> String {
+    format!("<a href=\"/path/{}\"><code>{}</code></a>", escape(id), escape(store_path))
+}
+
+/// Appends a table of label and value rows; values have to be escaped already.
+fn fields_table(html: &mut String, rows: &[(&str, String)]) {
+    html.push_str("<table>\n");
+    for (label, value) in rows {
+        let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, value);
+    }
+    html.push_str("</table>\n");
+}
+
+#[rocket::get("/")]
+async fn index(conn: DbConn) -> Result<Html<String>> {
+    let (usage, chunks, pins, runs) = db_run!(conn, |c| {
+        Ok::<_, diesel::result::Error>((c.usage()?, c.chunk_stats()?, c.pins()?.len(), c.gc_runs(20)?))
+    })?;
+
+    let mut body = String::from("<h1>nyancache</h1>\n");
+    body.push_str(&search_form(""));
+
+    body.push_str("<h2>Usage</h2>\n");
+    let mut rows = vec![
+        ("Paths", usage.paths.to_string()),
+        ("NAR size", format_size(usage.nar_bytes)),
+        ("Compressed size before deduplication", format_size(usage.file_bytes)),
+        ("Pins", pins.to_string()),
+    ];
+    if chunks.chunks > 0 {
+        rows.push(("Chunks", format!("{} ({})", chunks.chunks, format_size(chunks.stored_bytes))));
+        rows.push(("Chunk dedup ratio", format!("{:.2}", chunks.dedup_ratio())));
+    }
+    fields_table(&mut body, &rows);
+
+    body.push_str("<h2>Garbage collections</h2>\n");
+    if runs.is_empty() {
+        body.push_str("<p>None yet.</p>\n");
+    } else {
+        body.push_str(
+            "<table>\n<tr><th>Started</th><th class=\"number\">Duration</th><th class=\"number\">Cache size</th>\
+             <th class=\"number\">Pinned</th><th class=\"number\">Evicted paths</th><th class=\"number\">Evicted</th></tr>\n",
+        );
+        for run in runs {
+            let _ = writeln!(
+                body,
+                "<tr><td>{}</td><td class=\"number\">{} s</td><td class=\"number\">{}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{}</td></tr>",
+                format_time(Some(run.started)),
+                run.finished - run.started,
+                format_size(run.total_bytes),
+                format_size(run.pinned_bytes),
+                run.evicted_paths,
+                format_size(run.evicted_bytes),
+            );
+        }
+        body.push_str("</table>\n");
+    }
+    Ok(page("Overview", &body))
+}
+
+#[rocket::get("/search?<q>")]
+async fn search(conn: DbConn, q: &str) -> Result<Html<String>> {
+    let text = q.trim().to_string();
+    let found = db_run!(conn, |c| c.search_paths(&text, SEARCH_LIMIT))?;
+
+    let mut body = format!("<h1>Search</h1>\n{}", search_form(q.trim()));
+    if found.is_empty() {
+        body.push_str("<p>No paths found.</p>\n");
+        return Ok(page("Search", &body));
+    }
+    if found.len() as i64 == SEARCH_LIMIT {
+        let _ = writeln!(body, "<p>Showing the first {} paths.</p>", SEARCH_LIMIT);
+    }
+    body.push_str(
+        "<table>\n<tr><th>Store path</th><th class=\"number\">NAR size</th><th>Last access</th></tr>\n",
+    );
+    for db_path in found {
+        let _ = writeln!(
+            body,
+            "<tr><td>{}</td><td class=\"number\">{}</td><td>{}</td></tr>",
+            path_link(&db_path.id, &db_path.path),
+            format_size(db_path.nar_size),
+            format_time(db_path.last_accessed),
+        );
+    }
+    body.push_str("</table>\n");
+    Ok(page("Search", &body))
+}
+
+#[rocket::get("/path/<id>")]
+async fn path(conn: DbConn, id: &str) -> Result<Html<String>> {
+    let key = id.to_string();
+    let (db_path, references, referrers, pin, chunked) = db_run!(conn, |c| {
+        let (db_path, references) = match c.load_path(&key)? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let reference_ids = references.iter().map(|reference| crate::gc::path_id(reference).to_string()).collect::<Vec<_>>();
+        let missing = c.missing_paths(&reference_ids)?;
+        let references = references
+            .into_iter()
+            .zip(reference_ids)
+            .map(|(reference, id)| (missing.contains(&id), reference, id))
+            .collect::<Vec<_>>();
+        let pin = c.find_pin(&key)?;
+        let chunked = match &db_path.url {
+            Some(url) => c.is_chunked(url)?,
+            None => false,
+        };
+        Ok::<_, diesel::result::Error>(Some((db_path, references, c.referrers(&key)?, pin, chunked)))
+    })?
+    .ok_or(Error::NotFound)?;
+
+    let mut body = format!("<h1><code>{}</code></h1>\n", escape(&db_path.path));
+    let _ = writeln!(
+        body,
+        "<p><a href=\"{}\">Browse files</a> &middot; <a href=\"/{}.narinfo\">narinfo</a> &middot; \
+         <a href=\"/api/closure/{}\">closure</a> &middot; <a href=\"/api/export/{}\">export</a></p>",
+        escape(&browse_url(&db_path.id, None)),
+        escape(&db_path.id),
+        escape(&db_path.id),
+        escape(&db_path.id),
+    );
+
+    let optional = |value: &Option<String>| value.as_deref().map_or_else(|| "-".to_string(), |v| format!("<code>{}</code>", escape(v)));
+    let storage = match chunked {
+        true => "chunked".to_string(),
+        false => "whole".to_string(),
+    };
+    fields_table(
+        &mut body,
+        &[
+            ("NarHash", format!("<code>{}</code>", escape(&db_path.nar_hash))),
+            ("NarSize", format_size(db_path.nar_size)),
+            ("URL", optional(&db_path.url)),
+            ("Compression", optional(&db_path.compression)),
+            ("FileHash", optional(&db_path.file_hash)),
+            ("FileSize", db_path.file_size.map_or_else(|| "-".to_string(), format_size)),
+            ("Storage", storage),
+            ("Deriver", optional(&db_path.deriver)),
+            ("CA", optional(&db_path.ca)),
+            ("Registered", format_time(db_path.registration_time)),
+            ("Last access", format_time(db_path.last_accessed)),
+            ("Downloads", db_path.hit_count.to_string()),
+        ],
+    );
+
+    body.push_str("<h2>Signatures</h2>\n");
+    body.push_str(&list(db_path.sigs.split(' ').filter(|sig| !sig.is_empty()).map(|sig| format!("<code>{}</code>", escape(sig)))));
+
+    body.push_str("<h2>Pin</h2>\n");
+    match pin {
+        Some(pin) => {
+            let expires = match pin.expires {
+                Some(expires) if expires <= now() => format!("expired {}", format_time(Some(expires))),
+                Some(expires) => format!("until {}", format_time(Some(expires))),
+                None => "forever".to_string(),
+            };
+            let note = pin.note.map(|note| format!(": {}", escape(&note))).unwrap_or_default();
+            let _ = writeln!(body, "<p>Pinned since {}, {}{}</p>", format_time(Some(pin.created)), expires, note);
+        }
+        None => body.push_str("<p>Not pinned.</p>\n"),
+    }
+
+    let _ = writeln!(body, "<h2>References ({})</h2>", references.len());
+    body.push_str(&list(references.iter().map(|(missing, reference, id)| match missing {
+        true => format!("<code>{}</code> (not cached)", escape(reference)),
+        false => path_link(id, reference),
+    })));
+
+    let _ = writeln!(body, "<h2>Referrers ({})</h2>", referrers.len());
+    body.push_str(&list(referrers.iter().map(|referrer: &DbPath| path_link(&referrer.id, &referrer.path))));
+
+    Ok(page(&db_path.path, &body))
+}
+
+/// An unordered list of already escaped items.
+fn list<I: IntoIterator<Item = String>>(items: I) -> String {
+    let items = items.into_iter().map(|item| format!("<li>{}</li>\n", item)).collect::<String>();
+    match items.is_empty() {
+        true => "<p>None.</p>\n".to_string(),
+        false => format!("<ul>\n{}</ul>\n", items),
+    }
+}